@@ -15,11 +15,17 @@ use std::path::PathBuf;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
+    // on the linker search path. The `ota` feature makes room for
+    // the bootloader and the DFU image.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory_x: &[u8] = if env::var_os("CARGO_FEATURE_OTA").is_some() {
+        include_bytes!("memory-ota.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -28,6 +34,10 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-ota.x");
+    // The advertised name is baked in at compile time.
+    println!("cargo:rerun-if-env-changed=SENSOR_NAME");
+    println!("cargo:rerun-if-env-changed=JITTER_SEED");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
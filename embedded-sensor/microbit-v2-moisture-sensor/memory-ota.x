@@ -0,0 +1,17 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Layout for OTA updates with the `ota` feature, see "Firmware updates" in README.md:
+     softdevice 156K, bootloader 24K, bootloader state 4K, active image, DFU image
+     (one page larger than the active one, as the bootloader's swap needs it) and the
+     8K settings storage, see `STORAGE_START` */
+  BOOTLOADER_STATE : ORIGIN = 0x00000000 + 180K, LENGTH = 4K
+  FLASH : ORIGIN = 0x00000000 + 184K, LENGTH = 158K
+  DFU : ORIGIN = 0x00000000 + 342K, LENGTH = 162K
+  RAM : ORIGIN = 0x20000000 + 12512, LENGTH = 128K - 12512
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
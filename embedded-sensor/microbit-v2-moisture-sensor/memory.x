@@ -1,6 +1,7 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000 + 156K, LENGTH = 512K - 156K
-  RAM : ORIGIN = 0x20000000 + 11488, LENGTH = 128K - 11488
+  /* The last 8K are left out for the settings storage, see `STORAGE_START` */
+  FLASH : ORIGIN = 0x00000000 + 156K, LENGTH = 512K - 156K - 8K
+  RAM : ORIGIN = 0x20000000 + 12512, LENGTH = 128K - 12512
 }
\ No newline at end of file
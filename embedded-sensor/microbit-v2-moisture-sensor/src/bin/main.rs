@@ -1,56 +1,818 @@
 #![no_std]
 #![no_main]
 #![feature(type_alias_impl_trait)]
+// Lets the `secure` feature pick characteristic attributes inside the GATT service macros.
+#![feature(cfg_eval)]
 
 use defmt_rtt as _; // global logger
 use embassy_nrf as _; // time driver
 use panic_probe as _;
 
+use core::cell::RefCell;
 use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicI16, AtomicU16, AtomicU32, AtomicU8, Ordering};
 
 use defmt::{info, *};
 use embassy_executor::Spawner;
+use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive, Pin};
 use embassy_nrf::peripherals::SAADC;
-use embassy_nrf::saadc::{AnyInput, Input, Saadc};
+use embassy_nrf::saadc::{AnyInput, Input, Saadc, VddInput};
+use embassy_nrf::peripherals::WDT;
+use embassy_nrf::wdt::{self, Watchdog, WatchdogHandle};
 use embassy_nrf::{bind_interrupts, interrupt, saadc};
 use embassy_nrf::interrupt::Interrupt;
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use futures::future::{select, Either};
 use futures::pin_mut;
+use nrf_softdevice::ble::gatt_server::{NotifyValueError, RegisterError, RunError, WriteOp};
 use nrf_softdevice::ble::{gatt_server, peripheral, Connection};
-use nrf_softdevice::{raw, Softdevice};
+#[cfg(feature = "secure")]
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+#[cfg(feature = "secure")]
+use nrf_softdevice::ble::{EncryptionInfo, IdentityKey, MasterId};
+use microbit_v2_moisture_sensor::stack;
+use microbit_v2_moisture_sensor::storage::{self, Storage};
+use nrf_softdevice::{raw, Flash, RawError, Softdevice};
+#[cfg(feature = "ota")]
+use embassy_boot_nrf::{FirmwareUpdater, State};
 
 bind_interrupts!(struct Irqs {
     SAADC => saadc::InterruptHandler;
 });
 
-/// Initializes the SAADC peripheral in single-ended mode on the given pin.
-fn init_adc(adc_pin: AnyInput, adc: SAADC) -> Saadc<'static, 1> {
-    // Then we initialize the ADC. We are only using one channel in this example.
+/// Number of moisture probes, a second one on P0_04 (edge pin 2) with the `dual-probe` feature.
+#[cfg(not(feature = "dual-probe"))]
+const PROBE_COUNT: usize = 1;
+#[cfg(feature = "dual-probe")]
+const PROBE_COUNT: usize = 2;
+
+/// SAADC channels, in the order they are configured in `init_adc`: the probes, then VDD.
+const VDD_CHANNEL: usize = PROBE_COUNT;
+const CHANNEL_COUNT: usize = PROBE_COUNT + 1;
+
+/// Raw counts from either end of the scale within which a probe counts as disconnected.
+const PROBE_RAIL_MARGIN: i16 = 16;
+/// Raw probe values outside of this range come from a disconnected probe,
+/// whose floating or shorted input sits near either end of the scale.
+const PROBE_PLAUSIBLE_RAW: core::ops::RangeInclusive<i16> =
+    PROBE_RAIL_MARGIN..=(ADC_FULL_SCALE + 1 - PROBE_RAIL_MARGIN);
+
+/// Set when the last reading left out an implausible probe.
+static PROBE_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Below this supply voltage the battery is considered nearly empty.
+const LOW_BATTERY_THRESHOLD_MV: u16 = 2300;
+/// Whether to sample less often on a low battery to stretch its remaining life.
+const LOW_BATTERY_REDUCED_SAMPLING: bool = true;
+
+/// Set once the supply voltage dropped below `LOW_BATTERY_THRESHOLD_MV`.
+static BATTERY_LOW: AtomicBool = AtomicBool::new(false);
+
+/// Initializes the SAADC peripheral in single-ended mode on the given probe pins and on VDD.
+fn init_adc(probe_pins: [AnyInput; PROBE_COUNT], adc: SAADC) -> Saadc<'static, CHANNEL_COUNT> {
+    // Hardware oversampling stays off: with several channels scanned the SAADC
+    // averages samples across them unless every channel is in burst mode, which
+    // the driver doesn't configure. Readings average `OVERSAMPLING` scans instead.
     let config = saadc::Config::default();
-    let channel_cfg = saadc::ChannelConfig::single_ended(adc_pin.degrade_saadc());
+    // Supply voltage, used to estimate the battery level.
+    let vdd_cfg = saadc::ChannelConfig::single_ended(VddInput);
+    #[cfg(not(feature = "dual-probe"))]
+    let channel_cfgs = {
+        let [probe] = probe_pins;
+        [saadc::ChannelConfig::single_ended(probe), vdd_cfg]
+    };
+    #[cfg(feature = "dual-probe")]
+    let channel_cfgs = {
+        let [probe, second_probe] = probe_pins;
+        [
+            saadc::ChannelConfig::single_ended(probe),
+            saadc::ChannelConfig::single_ended(second_probe),
+            vdd_cfg,
+        ]
+    };
     interrupt::SAADC::set_priority(interrupt::Priority::P3);
-    let saadc = saadc::Saadc::new(adc, Irqs, config, [channel_cfg]);
+    let saadc = saadc::Saadc::new(adc, Irqs, config, channel_cfgs);
     saadc
 }
 
-/// Reads the current ADC value every second and notifies the connected client.
-async fn notify_adc_value<'a>(saadc: &'a mut Saadc<'_, 1>, server: &'a Server, connection: &'a Connection) {
+/// How often the SAADC is calibrated again, as its offset drifts while the board warms up or cools down.
+const ADC_CALIBRATION_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Uptime in seconds at the last SAADC calibration.
+static ADC_CALIBRATED_AT: AtomicU32 = AtomicU32::new(0);
+
+/// Calibrates the SAADC offset, waiting for the calibration to finish.
+async fn calibrate_adc(saadc: &mut Saadc<'_, CHANNEL_COUNT>) {
+    saadc.calibrate().await;
+    ADC_CALIBRATED_AT.store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+    info!("SAADC calibrated");
+}
+
+/// Whether `ADC_CALIBRATION_INTERVAL` passed since the last SAADC calibration.
+fn adc_calibration_due() -> bool {
+    let elapsed = (Instant::now().as_secs() as u32).wrapping_sub(ADC_CALIBRATED_AT.load(Ordering::Relaxed));
+    elapsed as u64 >= ADC_CALIBRATION_INTERVAL.as_secs()
+}
+
+/// Averages the plausible probe values, returning the average and whether a probe was left out.
+///
+/// When no probe is plausible, all of them are averaged so the reading still shows what happened.
+fn combine_probes(values: &[i16]) -> (i16, bool) {
+    let (sum, count) = values
+        .iter()
+        .filter(|value| PROBE_PLAUSIBLE_RAW.contains(value))
+        .fold((0i32, 0i32), |(sum, count), value| (sum + *value as i32, count + 1));
+    if count == 0 {
+        let sum: i32 = values.iter().map(|value| *value as i32).sum();
+        return ((sum / values.len() as i32) as i16, true);
+    }
+    ((sum / count) as i16, count < values.len() as i32)
+}
+
+/// Converts a raw VDD sample to millivolts.
+///
+/// The channel uses the default 1/6 gain and the internal 0.6V reference, so
+/// the full scale corresponds to 3.6V.
+fn vdd_raw_to_mv(raw: i16) -> u16 {
+    (raw.max(0) as u32 * 3600 / (1 << ADC_RESOLUTION_BITS)) as u16
+}
+
+/// Updates `BATTERY_LOW` from a raw VDD sample, returning whether the battery is low.
+fn check_battery(vdd_raw_value: i16) -> bool {
+    let vdd_mv = vdd_raw_to_mv(vdd_raw_value);
+    let low = vdd_mv < LOW_BATTERY_THRESHOLD_MV;
+    if low && !BATTERY_LOW.swap(low, Ordering::Relaxed) {
+        warn!("Battery is low: {=u16}mV", vdd_mv);
+        raise_alert(ALERT_LOW_BATTERY);
+    } else if !low {
+        BATTERY_LOW.store(false, Ordering::Relaxed);
+    }
+    low
+}
+
+/// Settle delay used until one is configured, long enough for common resistive and capacitive probes.
+const DEFAULT_SETTLE_DELAY_US: u32 = 10_000;
+/// Longest settle delay accepted, anything longer only wastes power.
+const MAX_SETTLE_DELAY_US: u32 = 1_000_000;
+
+/// Time between powering the probes up and sampling them, in microseconds.
+static SETTLE_DELAY_US: AtomicU32 = AtomicU32::new(DEFAULT_SETTLE_DELAY_US);
+
+/// Restores the settle delay persisted by a previous `SettleDelayWrite`, if any.
+async fn load_settle_delay(storage: &mut Storage<Flash>) {
+    match storage.read(KEY_SETTLE_DELAY).await {
+        Ok(Some(delay_us)) => SETTLE_DELAY_US.store(delay_us.min(MAX_SETTLE_DELAY_US), Ordering::Relaxed),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the settle delay: {:?}", e),
+    }
+    info!("Settle delay: {=u32}us", SETTLE_DELAY_US.load(Ordering::Relaxed));
+}
+
+/// Sample interval used until one is configured.
+const DEFAULT_SAMPLE_INTERVAL_SECS: u16 = 10;
+/// Range of accepted sample intervals, from quick feedback while calibrating to an hour.
+const SAMPLE_INTERVAL_SECS: core::ops::RangeInclusive<u16> = 1..=3600;
+/// Sample interval on a low battery, unless a longer one is configured.
+const LOW_BATTERY_SAMPLE_INTERVAL_SECS: u16 = 60;
+
+/// Time between two readings, in seconds.
+static SAMPLE_INTERVAL: AtomicU16 = AtomicU16::new(DEFAULT_SAMPLE_INTERVAL_SECS);
+
+/// Largest random shift of every wait between samples, either way, so sensors
+/// started together don't keep notifying a shared central at the same moment.
+const SAMPLE_JITTER_MS: u32 = 1000;
+
+/// State of the xorshift generator of the sample jitter, never 0.
+static JITTER_STATE: AtomicU32 = AtomicU32::new(1);
+
+/// Seeds the sample jitter: with `JITTER_SEED` when set at build time, for
+/// reproducible timing, or from the softdevice's random number generator.
+fn seed_jitter(sd: &Softdevice) {
+    let seed = match option_env!("JITTER_SEED").and_then(|seed| seed.parse::<u32>().ok()) {
+        Some(seed) => seed,
+        None => {
+            let mut bytes = [0u8; 4];
+            if let Err(e) = nrf_softdevice::random_bytes(sd, &mut bytes) {
+                warn!("Failed to seed the sample jitter: {:?}", e);
+            }
+            u32::from_le_bytes(bytes)
+        }
+    };
+    JITTER_STATE.store(seed.max(1), Ordering::Relaxed);
+}
+
+/// Next random shift of the sample timer within `±SAMPLE_JITTER_MS`, in milliseconds.
+fn next_jitter_ms() -> i64 {
+    let mut x = JITTER_STATE.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    JITTER_STATE.store(x, Ordering::Relaxed);
+    (x % (2 * SAMPLE_JITTER_MS + 1)) as i64 - SAMPLE_JITTER_MS as i64
+}
+
+/// Clamps a sample interval into `SAMPLE_INTERVAL_SECS`.
+fn clamp_sample_interval(secs: u16) -> u16 {
+    secs.clamp(*SAMPLE_INTERVAL_SECS.start(), *SAMPLE_INTERVAL_SECS.end())
+}
+
+/// Restores the sample interval persisted by a previous `SampleIntervalWrite`, if any.
+async fn load_sample_interval(storage: &mut Storage<Flash>) {
+    match storage.read(KEY_SAMPLE_INTERVAL).await {
+        Ok(Some(secs)) => SAMPLE_INTERVAL.store(clamp_sample_interval(secs as u16), Ordering::Relaxed),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the sample interval: {:?}", e),
+    }
+    info!("Sample interval: {=u16}s", SAMPLE_INTERVAL.load(Ordering::Relaxed));
+}
+
+/// Readings within this many raw ADC counts of the last notified one aren't
+/// notified, 0 notifies every reading.
+static NOTIFY_DELTA: AtomicU16 = AtomicU16::new(0);
+/// Longest time without a reading notification, so the central can tell the sensor is alive.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Restores the notify delta persisted by a previous `NotifyDeltaWrite`, if any.
+async fn load_notify_delta(storage: &mut Storage<Flash>) {
+    match storage.read(KEY_NOTIFY_DELTA).await {
+        Ok(Some(delta)) => NOTIFY_DELTA.store(delta as u16, Ordering::Relaxed),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the notify delta: {:?}", e),
+    }
+    info!("Notify delta: {=u16}", NOTIFY_DELTA.load(Ordering::Relaxed));
+}
+
+/// Last notified reading and when it was notified, deciding whether the next one is worth notifying.
+struct ChangeFilter {
+    last: Option<(i16, Instant)>,
+}
+
+impl ChangeFilter {
+    fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Whether `value` differs enough from the last notified reading, or the keepalive is due.
+    fn should_notify(&self, value: i16) -> bool {
+        match self.last {
+            Some((last, at)) => {
+                let delta = (value as i32 - last as i32).unsigned_abs();
+                delta > NOTIFY_DELTA.load(Ordering::Relaxed) as u32 || at.elapsed() >= KEEPALIVE_INTERVAL
+            }
+            None => true,
+        }
+    }
+
+    fn notified(&mut self, value: i16) {
+        self.last = Some((value, Instant::now()));
+    }
+}
+
+/// Supply of the probes, switched from edge pin 16 (P1_02) with the `probe-power`
+/// feature so they only draw current while sampled. Without the feature the
+/// probes are powered from 3V all the time.
+struct ProbePower<'d> {
+    pin: Option<Output<'d, AnyPin>>,
+}
+
+impl<'d> ProbePower<'d> {
+    fn new(pin: Option<AnyPin>) -> Self {
+        Self {
+            pin: pin.map(|pin| Output::new(pin, Level::Low, OutputDrive::HighDrive)),
+        }
+    }
+
+    /// Powers the probes up and waits for their output to settle.
+    async fn on(&mut self) {
+        if let Some(pin) = self.pin.as_mut() {
+            pin.set_high();
+        }
+        Timer::after(Duration::from_micros(SETTLE_DELAY_US.load(Ordering::Relaxed).into())).await;
+    }
+
+    fn off(&mut self) {
+        if let Some(pin) = self.pin.as_mut() {
+            pin.set_low();
+        }
+    }
+}
+
+/// Number of samples averaged into every reading, taken back-to-back while
+/// the probes are powered. Doubling it halves the noise's variance.
+const OVERSAMPLING: usize = 8;
+
+/// Mean and standard deviation of raw samples, the latter rounded down.
+fn mean_and_std_dev(samples: &[i16]) -> (i16, u16) {
+    let n = samples.len() as i64;
+    let sum: i64 = samples.iter().map(|sample| *sample as i64).sum();
+    let sum_sq: i64 = samples.iter().map(|sample| (*sample as i64).pow(2)).sum();
+    let variance = (sum_sq - sum * sum / n) / n;
+    ((sum / n) as i16, isqrt(variance.max(0) as u64).min(u16::MAX as u64) as u16)
+}
+
+/// Integer square root, rounded down.
+fn isqrt(value: u64) -> u64 {
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    let mut rest = value;
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// Samples every SAADC channel once.
+async fn sample_all(saadc: &mut Saadc<'_, CHANNEL_COUNT>) -> [i16; CHANNEL_COUNT] {
+    let mut buf = [0i16; CHANNEL_COUNT];
+    saadc.sample(&mut buf).await;
+    buf
+}
+
+/// Side of the micro:bit LED matrix.
+const MATRIX_SIZE: usize = 5;
+
+/// The micro:bit's 5x5 LED matrix, showing the BLE state at a glance: the
+/// top left LED while advertising, the four corners while connected and, once
+/// notifications are enabled, the moisture as a bar of lit rows growing from
+/// the bottom. It blinks its center LED when the battery is low.
+///
+/// An LED lights up while its row is high and its column low, so every set of
+/// rows can be shown crossed with a set of columns without multiplexing.
+struct LedMatrix<'d> {
+    /// Top to bottom.
+    rows: [Output<'d, AnyPin>; MATRIX_SIZE],
+    /// Left to right.
+    cols: [Output<'d, AnyPin>; MATRIX_SIZE],
+    /// Masks of the rows and columns currently lit, bit 0 being the top row or the left column.
+    lit: (u8, u8),
+}
+
+/// Rows and columns of the patterns, see `LedMatrix::light`.
+const PATTERN_ADVERTISING: (u8, u8) = (0b00001, 0b00001);
+const PATTERN_CONNECTED: (u8, u8) = (0b10001, 0b10001);
+/// Shown instead of an empty bar, so sampling is still visible on dry soil.
+const PATTERN_EMPTY_BAR: (u8, u8) = (0b10000, 0b00100);
+
+impl<'d> LedMatrix<'d> {
+    fn new(rows: [AnyPin; MATRIX_SIZE], cols: [AnyPin; MATRIX_SIZE]) -> Self {
+        Self {
+            rows: rows.map(|pin| Output::new(pin, Level::Low, OutputDrive::Standard)),
+            cols: cols.map(|pin| Output::new(pin, Level::High, OutputDrive::Standard)),
+            lit: (0, 0),
+        }
+    }
+
+    /// Lights the LEDs at the crossings of the `rows` and `cols` masks.
+    fn light(&mut self, (rows, cols): (u8, u8)) {
+        self.lit = (rows, cols);
+        for (i, row) in self.rows.iter_mut().enumerate() {
+            row.set_level(if rows & 1 << i != 0 { Level::High } else { Level::Low });
+        }
+        for (i, col) in self.cols.iter_mut().enumerate() {
+            col.set_level(if cols & 1 << i != 0 { Level::Low } else { Level::High });
+        }
+    }
+
+    /// Lights the bottom `rows` rows, or `PATTERN_EMPTY_BAR` at 0.
+    fn show_bar(&mut self, rows: usize) {
+        let rows = rows.min(MATRIX_SIZE);
+        if rows == 0 {
+            self.light(PATTERN_EMPTY_BAR);
+        } else {
+            let mask = ((1u8 << rows) - 1) << (MATRIX_SIZE - rows);
+            self.light((mask, (1 << MATRIX_SIZE) - 1));
+        }
+    }
+
+    /// Shows that the sensor is advertising, waiting for a client.
+    fn show_advertising(&mut self) {
+        self.light(PATTERN_ADVERTISING);
+    }
+
+    /// Shows that a client is connected without listening to the readings.
+    fn show_connected(&mut self) {
+        self.light(PATTERN_CONNECTED);
+    }
+
+    /// Three short blinks of the center LED, distinguishable from anything else the board shows.
+    async fn blink_low_battery(&mut self) {
+        let lit = self.lit;
+        self.light((0, 0));
+        let center = MATRIX_SIZE / 2;
+        self.cols[center].set_low();
+        for _ in 0..3 {
+            self.rows[center].set_high();
+            Timer::after(Duration::from_millis(50)).await;
+            self.rows[center].set_low();
+            Timer::after(Duration::from_millis(150)).await;
+        }
+        self.light(lit);
+    }
+}
+
+/// Moisture of a raw reading in thousandths: between the calibration points
+/// once both are known, or over the whole ADC scale otherwise.
+fn moisture_milli(raw_value: i16) -> i32 {
+    let (low, high) = match calibration_points() {
+        Some((dry, wet)) => (dry as i32, wet as i32),
+        None => (0, ADC_FULL_SCALE as i32),
+    };
+    ((raw_value as i32 - low) * 1000 / (high - low)).clamp(0, 1000)
+}
+
+/// Dry and wet calibration points, once both are known and apart.
+fn calibration_points() -> Option<(i16, i16)> {
+    let dry = DRY_RAW_VALUE.load(Ordering::Relaxed);
+    let wet = WET_RAW_VALUE.load(Ordering::Relaxed);
+    if dry == NO_CALIBRATION_POINT || wet == NO_CALIBRATION_POINT || dry == wet {
+        None
+    } else {
+        Some((dry, wet))
+    }
+}
+
+/// Rows of the moisture bar for a raw reading, see `moisture_milli`.
+fn bar_rows(raw_value: i16) -> usize {
+    ((moisture_milli(raw_value) * MATRIX_SIZE as i32 + 500) / 1000) as usize
+}
+
+/// Whether the connected client enabled soil moisture notifications.
+static NOTIFICATIONS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the connected client enabled notifications of the Environmental Sensing humidity.
+static ESS_NOTIFICATIONS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether the connected client enabled notifications of the moisture percent.
+static PERCENT_NOTIFICATIONS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Raised whenever the client enables or disables notifications of the reading.
+static NOTIFICATIONS_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Raised by a write to the read-now characteristic, cutting the wait for the next sample short.
+static READ_NOW: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Whether the client listens to the reading, in any of its forms.
+fn notifications_enabled() -> bool {
+    NOTIFICATIONS_ENABLED.load(Ordering::Relaxed)
+        || ESS_NOTIFICATIONS_ENABLED.load(Ordering::Relaxed)
+        || PERCENT_NOTIFICATIONS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Why a notification wasn't sent, logged to tell a flaky link apart from a client not listening.
+#[derive(defmt::Format)]
+enum NotifyError {
+    /// The client didn't enable notifications of the characteristic, or its CCCDs aren't restored yet.
+    NotEnabled,
+    /// The link dropped before the notification was queued.
+    Disconnected,
+    /// The SoftDevice's notification queue is full.
+    Busy,
+    Other(RawError),
+}
+
+impl From<NotifyValueError> for NotifyError {
+    fn from(err: NotifyValueError) -> Self {
+        match err {
+            NotifyValueError::Disconnected => NotifyError::Disconnected,
+            NotifyValueError::Raw(RawError::InvalidState | RawError::BleGattsSysAttrMissing) => NotifyError::NotEnabled,
+            NotifyValueError::Raw(RawError::Busy | RawError::Resources) => NotifyError::Busy,
+            NotifyValueError::Raw(err) => NotifyError::Other(err),
+        }
+    }
+}
+
+/// Logs why notifying the `characteristic` failed; its value is set instead, still readable by the client.
+fn notify_failed(characteristic: &str, err: NotifyValueError) {
+    match NotifyError::from(err) {
+        // Expected for the forms of the reading the client doesn't listen to.
+        err @ NotifyError::NotEnabled => debug!("Not notifying {=str}: {}", characteristic, err),
+        err => warn!("Failed to notify {=str}: {}", characteristic, err),
+    }
+}
+
+/// Reads the current ADC value every `SAMPLE_INTERVAL` and notifies the connected client.
+///
+/// Nothing is sampled while the client has notifications of the reading disabled.
+async fn notify_adc_value<'a>(
+    saadc: &'a mut Saadc<'_, CHANNEL_COUNT>,
+    probe_power: &'a mut ProbePower<'_>,
+    display: &'a mut LedMatrix<'_>,
+    server: &'a Server,
+    connection: &'a Connection,
+) {
+    // Notifications start disabled on every new connection.
+    NOTIFICATIONS_ENABLED.store(false, Ordering::Relaxed);
+    ESS_NOTIFICATIONS_ENABLED.store(false, Ordering::Relaxed);
+    PERCENT_NOTIFICATIONS_ENABLED.store(false, Ordering::Relaxed);
+    NOTIFICATIONS_CHANGED.reset();
+    READ_NOW.reset();
+    let mut changes = ChangeFilter::new();
+    // Whether the central asked for this reading, which is notified even if it barely changed.
+    let mut requested = false;
+    // Last reading that passed `check_sample`, before the trim.
+    let mut previous = None;
     loop {
-        let mut buf = [0i16; 1];
-        saadc.sample(&mut buf).await;
+        enforce_min_conn_interval(connection);
 
-        // We only sampled one ADC channel.
-        let adc_raw_value: i16 = buf[0];
+        // A client that never subscribes, or a wedged GATT server, mustn't hold the sensor forever.
+        let deadline = Instant::now() + SUBSCRIBE_TIMEOUT;
+        while !notifications_enabled() {
+            display.show_connected();
+            expect_progress(SUBSCRIBE_TIMEOUT);
+            if let Either::Right(_) = select(NOTIFICATIONS_CHANGED.wait(), Timer::at(deadline)).await {
+                warn!("Client didn't subscribe in time, disconnecting");
+                let _ = connection.disconnect();
+                return;
+            }
+        }
+        // Sampling takes a moment only.
+        expect_progress(Duration::from_secs(0));
 
-        // Try and notify the connected client of the new ADC value.
-        match server.sms.soil_moisture_level_notify(connection, &adc_raw_value) {
-            Ok(_) => info!("Soil moisture adc_raw_value: {=i16}", &adc_raw_value),
-            Err(_) => unwrap!(server.sms.soil_moisture_level_set(&adc_raw_value)),
-        };
+        if TEST_PATTERN.load(Ordering::Relaxed) {
+            notify_test_pattern(server, connection);
+            expect_progress(TEST_PATTERN_INTERVAL);
+            Timer::after(TEST_PATTERN_INTERVAL).await;
+            continue;
+        }
+
+        // The calibration takes the place of this cycle's sample, the next one follows right away.
+        if adc_calibration_due() {
+            calibrate_adc(saadc).await;
+            continue;
+        }
+
+        let mut sample = sample_window(saadc, probe_power).await;
+        match check_sample(sample.value, previous) {
+            Ok(value) => sample.value = value,
+            Err(reason) => {
+                warn!("Discarded sample {=i16}: {}, sampling again", sample.value, reason);
+                let first = sample.value;
+                sample = sample_window(saadc, probe_power).await;
+                // A jump confirmed by the second sample is a real change, e.g. watering.
+                match check_sample(sample.value, previous).or(check_sample(sample.value, Some(first))) {
+                    Ok(value) => sample.value = value,
+                    Err(reason) => {
+                        warn!("Discarded sample {=i16} again: {}, skipping this reading", sample.value, reason);
+                        requested = wait_next_sample(BATTERY_LOW.load(Ordering::Relaxed)).await;
+                        continue;
+                    }
+                }
+            }
+        }
+        previous = Some(sample.value);
+        let SampledWindow { value: probe_value, noise, dropped, buf } = sample;
+
+        unwrap!(server.diagnostics.free_stack_set(&stack::free_bytes()));
+
+        if dropped && !PROBE_DROPPED.load(Ordering::Relaxed) {
+            warn!("Left out an implausible probe: {=[i16]}", &buf[..PROBE_COUNT]);
+            raise_alert(ALERT_PROBE_DROPPED);
+        }
+        PROBE_DROPPED.store(dropped, Ordering::Relaxed);
+
+        let adc_raw_value: i16 = Trim::load().apply(probe_value);
+        LAST_RAW_VALUE.store(adc_raw_value, Ordering::Relaxed);
+        let battery_low = check_battery(buf[VDD_CHANNEL]);
+        unwrap!(server.diagnostics.supply_voltage_set(&vdd_raw_to_mv(buf[VDD_CHANNEL])));
+
+        let flags = status_flags();
+        if flags != unwrap!(server.sms.status_get()) {
+            notify_status(server, connection);
+        }
+        indicate_alerts(server, connection);
+
+        let humidity = (moisture_milli(adc_raw_value) * 10) as u16;
+        // Uncalibrated, only the raw reading means something.
+        let percent = calibration_points().map(|_| ((moisture_milli(adc_raw_value) + 5) / 10) as u8);
+        #[cfg(feature = "die-temperature")]
+        let temperature = die_temperature();
+        // Try and notify the connected client of the new ADC value, unless it barely changed.
+        if requested || changes.should_notify(adc_raw_value) {
+            match server.sms.soil_moisture_level_notify(connection, &adc_raw_value) {
+                Ok(_) => {
+                    info!("Soil moisture adc_raw_value: {=i16}", &adc_raw_value);
+                    changes.notified(adc_raw_value);
+                }
+                Err(err) => {
+                    notify_failed("soil moisture", err);
+                    unwrap!(server.sms.soil_moisture_level_set(&adc_raw_value));
+                }
+            };
+            if let Err(err) = server.sms.noise_notify(connection, &noise) {
+                notify_failed("noise", err);
+                unwrap!(server.sms.noise_set(&noise));
+            }
+            match server.ess.humidity_notify(connection, &humidity) {
+                Ok(_) => changes.notified(adc_raw_value),
+                Err(err) => {
+                    notify_failed("humidity", err);
+                    unwrap!(server.ess.humidity_set(&humidity));
+                }
+            }
+            if let Some(percent) = percent {
+                match server.sms.moisture_percent_notify(connection, &percent) {
+                    Ok(_) => changes.notified(adc_raw_value),
+                    Err(err) => {
+                        notify_failed("moisture percent", err);
+                        unwrap!(server.sms.moisture_percent_set(&percent));
+                    }
+                }
+            }
+            #[cfg(feature = "die-temperature")]
+            if let Some(temperature) = temperature {
+                if let Err(err) = server.ess.temperature_notify(connection, &temperature) {
+                    notify_failed("temperature", err);
+                    unwrap!(server.ess.temperature_set(&temperature));
+                }
+            }
+        } else {
+            // Still readable by the central.
+            unwrap!(server.sms.soil_moisture_level_set(&adc_raw_value));
+            unwrap!(server.sms.noise_set(&noise));
+            unwrap!(server.ess.humidity_set(&humidity));
+            if let Some(percent) = percent {
+                unwrap!(server.sms.moisture_percent_set(&percent));
+            }
+            #[cfg(feature = "die-temperature")]
+            if let Some(temperature) = temperature {
+                unwrap!(server.ess.temperature_set(&temperature));
+            }
+        }
+
+        display.show_bar(bar_rows(adc_raw_value));
+        if battery_low {
+            display.blink_low_battery().await;
+        }
+
+        requested = wait_next_sample(battery_low).await;
+    }
+}
+
+/// Temperature of the nRF52 die in hundredths of a degree Celsius, `None` when the softdevice fails to measure it.
+#[cfg(feature = "die-temperature")]
+fn die_temperature() -> Option<i16> {
+    // In steps of 0.25 degrees.
+    let mut quarter_degrees: i32 = 0;
+    let ret = unsafe { raw::sd_temp_get(&mut quarter_degrees) };
+    if ret != raw::NRF_SUCCESS {
+        warn!("Failed to measure the die temperature: {=u32}", ret);
+        return None;
+    }
+    Some((quarter_degrees * 25) as i16)
+}
+
+/// Waits for the next sample, returning whether the central asked for it with the read-now characteristic.
+async fn wait_next_sample(battery_low: bool) -> bool {
+    // Read on every iteration, so a newly written interval applies from the next sample.
+    let mut interval_secs = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+    if battery_low && LOW_BATTERY_REDUCED_SAMPLING {
+        interval_secs = interval_secs.max(LOW_BATTERY_SAMPLE_INTERVAL_SECS);
+    }
+    let interval_ms = (interval_secs as i64 * 1000 + next_jitter_ms()).max(0);
+    let interval = Duration::from_millis(interval_ms as u64);
+    expect_progress(interval);
+    let timer_fut = Timer::after(interval);
+    let read_now_fut = READ_NOW.wait();
+    // Disabled notifications show on the display right away.
+    let changed_fut = NOTIFICATIONS_CHANGED.wait();
+    pin_mut!(timer_fut);
+    pin_mut!(read_now_fut);
+    pin_mut!(changed_fut);
+    let woken = select(select(timer_fut, read_now_fut), changed_fut).await;
+    let requested = matches!(woken, Either::Left((Either::Right(_), _)));
+    if requested {
+        info!("Reading requested by the central");
+    }
+    requested
+}
+
+/// A reading averaged over a window of samples, whose spread tells how noisy the probe is.
+struct SampledWindow {
+    value: i16,
+    noise: u16,
+    /// Whether an implausible probe was left out of any sample.
+    dropped: bool,
+    /// Last sample of every channel.
+    buf: [i16; CHANNEL_COUNT],
+}
+
+/// Powers the probes up and averages `OVERSAMPLING` samples of them.
+async fn sample_window(saadc: &mut Saadc<'_, CHANNEL_COUNT>, probe_power: &mut ProbePower<'_>) -> SampledWindow {
+    let mut window = [0i16; OVERSAMPLING];
+    let mut buf = [0i16; CHANNEL_COUNT];
+    let mut dropped = false;
+    probe_power.on().await;
+    for sample in window.iter_mut() {
+        buf = sample_all(saadc).await;
+        let (probe_value, probe_dropped) = combine_probes(&buf[..PROBE_COUNT]);
+        *sample = probe_value;
+        dropped |= probe_dropped;
+    }
+    probe_power.off();
+    let (value, noise) = mean_and_std_dev(&window);
+    SampledWindow { value, noise, dropped, buf }
+}
+
+/// Resolution of the SAADC conversions, the default of `saadc::Config`.
+const ADC_RESOLUTION_BITS: u8 = 12;
+/// Largest raw reading, that of a probe at the ADC reference.
+const ADC_FULL_SCALE: i16 = (1 << ADC_RESOLUTION_BITS) - 1;
+/// Values a SAADC conversion can take.
+const ADC_VALID_RAW: core::ops::RangeInclusive<i16> = 0..=ADC_FULL_SCALE;
+/// Negative readings down to this are the SAADC's offset on a grounded or
+/// bone-dry probe rather than outliers, and read as 0.
+const ADC_NEGATIVE_NOISE: i16 = -64;
+/// Largest change from the previous reading taken at face value, in raw ADC
+/// counts. A bigger jump, like the near full-scale outlier sometimes following
+/// a SAADC calibration, is only believed once a second sample confirms it.
+const MAX_READING_JUMP: u16 = 1000;
+
+/// Checks a reading against the ADC range and the `previous` one, returning
+/// it clamped into `ADC_VALID_RAW`, or telling why it's implausible.
+fn check_sample(value: i16, previous: Option<i16>) -> Result<i16, &'static str> {
+    let value = match value {
+        value if ADC_VALID_RAW.contains(&value) => value,
+        value if (ADC_NEGATIVE_NOISE..0).contains(&value) => 0,
+        _ => return Err("outside of the ADC range"),
+    };
+    match previous {
+        Some(previous) if value.abs_diff(previous) > MAX_READING_JUMP => Err("jumped from the previous reading"),
+        _ => Ok(value),
+    }
+}
+
+/// Test pattern readings form a sawtooth counting `0..TEST_PATTERN_PERIOD`,
+/// so every reading is its own sequence number.
+const TEST_PATTERN_PERIOD: i16 = 4096;
+/// Test pattern readings are notified much faster than real ones.
+const TEST_PATTERN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set while the test pattern is notified instead of real ADC values.
+static TEST_PATTERN: AtomicBool = AtomicBool::new(false);
+/// Next test pattern reading, restarted at 0 whenever the test pattern gets enabled.
+static TEST_PATTERN_NEXT: AtomicI16 = AtomicI16::new(0);
+
+/// Notifies the next test pattern reading instead of a real one.
+fn notify_test_pattern(server: &Server, connection: &Connection) {
+    let value = TEST_PATTERN_NEXT.load(Ordering::Relaxed);
+    TEST_PATTERN_NEXT.store((value + 1) % TEST_PATTERN_PERIOD, Ordering::Relaxed);
+
+    match server.sms.soil_moisture_level_notify(connection, &value) {
+        Ok(_) => info!("Test pattern value: {=i16}", value),
+        Err(err) => {
+            notify_failed("test pattern", err);
+            unwrap!(server.sms.soil_moisture_level_set(&value));
+        }
+    };
+}
+
+/// Hardware watchdog timeout: the chip resets unless `watchdog_task` pets it in time.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often `watchdog_task` pets the watchdog, well within `WATCHDOG_TIMEOUT`.
+const WATCHDOG_PET_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Uptime in seconds until which the firmware counts as alive, see `expect_progress`.
+static ALIVE_UNTIL: AtomicU32 = AtomicU32::new(0);
+
+/// Tells the watchdog the caller waits for up to `wait` before making progress
+/// again. Missing the deadline by `WATCHDOG_TIMEOUT` resets the chip.
+fn expect_progress(wait: Duration) {
+    let until = Instant::now().as_secs() + wait.as_secs() + WATCHDOG_TIMEOUT.as_secs();
+    ALIVE_UNTIL.store(until.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+}
+
+/// Starts the hardware watchdog, which can't be stopped anymore until the next reset.
+fn start_watchdog(wdt: WDT) -> WatchdogHandle {
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = 32768 * WATCHDOG_TIMEOUT.as_secs() as u32;
+    // Halting on a breakpoint must not reset the chip under the debugger.
+    config.run_during_debug_halt = false;
+    match Watchdog::try_new(wdt, config) {
+        Ok((_watchdog, [handle])) => handle,
+        // Left running by the firmware before a soft reset, with another configuration.
+        Err(_) => defmt::panic!("Watchdog already running with another configuration"),
+    }
+}
 
-        // Sleep for one second.
-        Timer::after(Duration::from_secs(10)).await
+/// Pets the watchdog as long as the loops keep their `expect_progress` deadlines,
+/// so a wedged sampling or advertising loop, or a wedged executor, resets the chip.
+#[embassy_executor::task]
+async fn watchdog_task(mut handle: WatchdogHandle) -> ! {
+    loop {
+        if Instant::now().as_secs() < ALIVE_UNTIL.load(Ordering::Relaxed) as u64 {
+            handle.pet();
+        }
+        Timer::after(WATCHDOG_PET_INTERVAL).await;
     }
 }
 
@@ -59,32 +821,1259 @@ async fn softdevice_task(sd: &'static Softdevice) -> ! {
     sd.run().await
 }
 
+/// Every control command is an opcode followed by these magic bytes, so a
+/// stray write to the control characteristic can't reboot the board.
+const CONTROL_MAGIC: [u8; 3] = *b"MB!";
+
+/// Control command opcodes (first byte of the control characteristic).
+const CMD_REBOOT: u8 = 0x01;
+const CMD_CALIBRATION_BEGIN: u8 = 0x10;
+const CMD_CALIBRATION_CAPTURE_DRY: u8 = 0x11;
+const CMD_CALIBRATION_CAPTURE_WET: u8 = 0x12;
+const CMD_CALIBRATION_END: u8 = 0x13;
+const CMD_TEST_PATTERN_START: u8 = 0x20;
+const CMD_TEST_PATTERN_STOP: u8 = 0x21;
+const CMD_CONFIRM_UPDATE: u8 = 0x30;
+const CMD_UPDATE_BEGIN: u8 = 0x31;
+const CMD_UPDATE_FINISH: u8 = 0x32;
+const CMD_OPEN_PAIRING: u8 = 0x40;
+
+/// Control command status codes, reported back in the second byte of the
+/// control characteristic after a command was handled.
+const STATUS_OK: u8 = 0x00;
+const STATUS_UNSUPPORTED: u8 = 0x01;
+const STATUS_BAD_MAGIC: u8 = 0x02;
+const STATUS_INVALID_STATE: u8 = 0x03;
+
+/// Bits of the status characteristic.
+const FLAG_CALIBRATING: u8 = 1 << 0;
+const FLAG_LOW_BATTERY: u8 = 1 << 1;
+const FLAG_TEST_PATTERN: u8 = 1 << 2;
+const FLAG_PROBE_DROPPED: u8 = 1 << 3;
+const FLAG_UPDATE_PENDING: u8 = 1 << 4;
+
+/// Last sampled raw ADC value, used when capturing calibration points.
+static LAST_RAW_VALUE: AtomicI16 = AtomicI16::new(0);
+
+/// Set while dry/wet calibration points are being captured, readings are meaningless meanwhile.
+static CALIBRATING: AtomicBool = AtomicBool::new(false);
+
+/// Raw ADC values of completely dry and completely wet soil, `NO_CALIBRATION_POINT` when unknown.
+static DRY_RAW_VALUE: AtomicI16 = AtomicI16::new(NO_CALIBRATION_POINT);
+static WET_RAW_VALUE: AtomicI16 = AtomicI16::new(NO_CALIBRATION_POINT);
+
+/// Points captured by the calibration in progress, committed by `CMD_CALIBRATION_END`.
+static CAPTURED_DRY_RAW_VALUE: AtomicI16 = AtomicI16::new(NO_CALIBRATION_POINT);
+static CAPTURED_WET_RAW_VALUE: AtomicI16 = AtomicI16::new(NO_CALIBRATION_POINT);
+
+const NO_CALIBRATION_POINT: i16 = i16::MIN;
+
+/// Persists the calibration points, packed as little-endian `[dry: i16, wet: i16]`.
+fn persist_calibration() {
+    let dry = DRY_RAW_VALUE.load(Ordering::Relaxed);
+    let wet = WET_RAW_VALUE.load(Ordering::Relaxed);
+    persist(KEY_CALIBRATION, dry as u16 as u32 | (wet as u16 as u32) << 16);
+}
+
+/// Restores the calibration points persisted by a previous calibration, if any.
+///
+/// Without them, e.g. on the first boot, only the raw reading is meaningful.
+async fn load_calibration(storage: &mut Storage<Flash>) {
+    match storage.read(KEY_CALIBRATION).await {
+        Ok(Some(packed)) => {
+            DRY_RAW_VALUE.store(packed as u16 as i16, Ordering::Relaxed);
+            WET_RAW_VALUE.store((packed >> 16) as u16 as i16, Ordering::Relaxed);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the calibration: {:?}", e),
+    }
+    match calibration_points() {
+        Some((dry, wet)) => info!("Calibration: dry={=i16}, wet={=i16}", dry, wet),
+        None => info!("Not calibrated"),
+    }
+}
+
+/// Refreshes the calibration point characteristics after a calibration changed.
+fn update_calibration(server: &Server) {
+    unwrap!(server.sms.dry_raw_set(&DRY_RAW_VALUE.load(Ordering::Relaxed)));
+    unwrap!(server.sms.wet_raw_set(&WET_RAW_VALUE.load(Ordering::Relaxed)));
+}
+
+/// Raised by the control command handler, the reboot itself happens in `reboot_task`.
+static REBOOT: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Resets the chip once a reboot was requested over GATT.
+///
+/// The reset is delayed a little so the write response (and the status
+/// written back to the control characteristic) can still reach the central,
+/// and waits for any flash operation in progress to complete.
+#[embassy_executor::task]
+async fn reboot_task() -> ! {
+    REBOOT.wait().await;
+    info!("Reboot requested, resetting...");
+    Timer::after(Duration::from_millis(500)).await;
+    let _storage = STORAGE.lock().await;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// How long a freshly installed image has to get confirmed before it's rolled back.
+#[cfg(feature = "ota")]
+const UPDATE_CONFIRM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Set while running a freshly installed image, which the bootloader rolls
+/// back on the next reset unless it's confirmed.
+static UPDATE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Raised by `CMD_CONFIRM_UPDATE`, the confirmation itself is written by `update_guard_task`.
+static CONFIRM_UPDATE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Buffer for bootloader state flash operations, which must be word aligned.
+#[cfg(feature = "ota")]
+#[repr(align(4))]
+struct AlignedBuffer([u8; 4]);
+
+/// Whether the bootloader just swapped a new image in, which is then on trial until confirmed.
+#[cfg(feature = "ota")]
+async fn is_trial_boot(storage: &mut Storage<Flash>) -> bool {
+    let mut aligned = AlignedBuffer([0; 4]);
+    match FirmwareUpdater::default().get_state(storage.flash(), &mut aligned.0).await {
+        Ok(state) => matches!(state, State::Swap),
+        Err(e) => {
+            warn!("Failed to read the bootloader state: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Confirms a freshly installed image on `CMD_CONFIRM_UPDATE`, or resets once
+/// `UPDATE_CONFIRM_TIMEOUT` passed without a confirmation, so the bootloader
+/// rolls the image back.
+#[cfg(feature = "ota")]
+#[embassy_executor::task]
+async fn update_guard_task() {
+    let deadline = Instant::now() + UPDATE_CONFIRM_TIMEOUT;
+    loop {
+        if let Either::Right(_) = select(CONFIRM_UPDATE.wait(), Timer::at(deadline)).await {
+            warn!("Update not confirmed in time, resetting to roll it back");
+            let _storage = STORAGE.lock().await;
+            cortex_m::peripheral::SCB::sys_reset()
+        }
+
+        let mut aligned = AlignedBuffer([0; 4]);
+        let mut storage = STORAGE.lock().await;
+        let storage = unwrap!(storage.as_mut());
+        match FirmwareUpdater::default().mark_booted(storage.flash(), &mut aligned.0).await {
+            Ok(()) => {
+                UPDATE_PENDING.store(false, Ordering::Relaxed);
+                info!("Update confirmed");
+                return;
+            }
+            // Still on trial, the central may retry until the deadline.
+            Err(e) => warn!("Failed to confirm the update: {:?}", e),
+        }
+    }
+}
+
+/// Most image bytes in one write of the DFU data characteristic, after the 4 byte offset.
+const DFU_CHUNK_SIZE: usize = 240;
+/// Size of the DFU partition, see `memory-ota.x`.
+const DFU_SIZE: u32 = 162 * 1024;
+
+/// Page of the uploaded image being received, written to the DFU partition once full.
+struct DfuPage {
+    buf: [u8; storage::PAGE_SIZE as usize],
+    len: usize,
+    /// Image offset of the page.
+    offset: u32,
+}
+
+impl DfuPage {
+    const EMPTY: DfuPage = DfuPage {
+        buf: [0xff; storage::PAGE_SIZE as usize],
+        len: 0,
+        offset: 0,
+    };
+}
+
+/// Set between `CMD_UPDATE_BEGIN` and `CMD_UPDATE_FINISH`.
+static DFU_ACTIVE: AtomicBool = AtomicBool::new(false);
+static DFU_PAGE: BlockingMutex<ThreadModeRawMutex, RefCell<DfuPage>> = BlockingMutex::new(RefCell::new(DfuPage::EMPTY));
+/// Raised when `DFU_PAGE` is full, the page is written by `write_update`.
+static DFU_PAGE_FULL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Raised by `CMD_UPDATE_FINISH`.
+static DFU_FINISH: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Appends a `[offset: u32, data]` chunk of the uploaded image to `DFU_PAGE`.
+///
+/// Chunks must come in order and not cross a page boundary. While a full page
+/// waits to be written, chunks are dropped: the central waits for the progress
+/// characteristic to reach the end of a page before sending the next one.
+fn receive_update_chunk(chunk: &[u8]) {
+    if !DFU_ACTIVE.load(Ordering::Relaxed) || chunk.len() < 4 {
+        warn!("Unexpected firmware chunk");
+        return;
+    }
+    let offset = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    let data = &chunk[4..];
+    DFU_PAGE.lock(|page| {
+        let mut page = page.borrow_mut();
+        let expected = page.offset + page.len as u32;
+        if offset != expected || page.len + data.len() > page.buf.len() || offset + data.len() as u32 > DFU_SIZE {
+            warn!("Dropped firmware chunk at {=u32}, expected {=u32}", offset, expected);
+            return;
+        }
+        let len = page.len;
+        page.buf[len..len + data.len()].copy_from_slice(data);
+        page.len += data.len();
+        if page.len == page.buf.len() {
+            DFU_PAGE_FULL.signal(());
+        }
+    });
+}
+
+/// Writes the pages of an upload to the DFU partition as they fill up, keeping
+/// the progress characteristic at the number of bytes written. Once the upload
+/// is finished, marks the image as updated and reboots into the bootloader.
+async fn write_update(server: &Server) {
+    #[cfg(not(feature = "ota"))]
+    {
+        let _ = server;
+        core::future::pending::<()>().await;
+    }
+    #[cfg(feature = "ota")]
+    loop {
+        let finished = match select(DFU_PAGE_FULL.wait(), DFU_FINISH.wait()).await {
+            Either::Left(_) => false,
+            Either::Right(_) => true,
+        };
+        let (buf, len, offset) = DFU_PAGE.lock(|page| {
+            let page = page.borrow();
+            (page.buf, page.len, page.offset)
+        });
+
+        let mut storage = STORAGE.lock().await;
+        let storage = unwrap!(storage.as_mut());
+        let mut updater = FirmwareUpdater::default();
+        if len > 0 {
+            // The bootloader copies whole pages, the tail of the last one stays erased.
+            if let Err(e) = updater.write_firmware(offset as usize, &buf, storage.flash(), buf.len()).await {
+                warn!("Failed to write the firmware page at {=u32}: {:?}", offset, e);
+                DFU_ACTIVE.store(false, Ordering::Relaxed);
+                continue;
+            }
+        }
+        let written = offset + len as u32;
+        DFU_PAGE.lock(|page| {
+            *page.borrow_mut() = DfuPage {
+                offset: written,
+                ..DfuPage::EMPTY
+            }
+        });
+        unwrap!(server.dfu.progress_set(&written));
+
+        if finished {
+            let mut aligned = AlignedBuffer([0; 4]);
+            match updater.mark_updated(storage.flash(), &mut aligned.0).await {
+                Ok(()) => {
+                    info!("Firmware upload of {=u32} bytes done, rebooting into the bootloader", written);
+                    REBOOT.signal(());
+                }
+                Err(e) => warn!("Failed to mark the firmware as updated: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Start of the settings storage, the last pages of the flash (excluded from `memory.x`).
+const STORAGE_START: u32 = 512 * 1024 - storage::REGION_SIZE;
+
+/// Storage keys of persisted values.
+const KEY_BOOT_COUNT: u16 = 0x0001;
+const KEY_TRIM: u16 = 0x0002;
+const KEY_SETTLE_DELAY: u16 = 0x0003;
+const KEY_SAMPLE_INTERVAL: u16 = 0x0004;
+const KEY_NOTIFY_DELTA: u16 = 0x0005;
+const KEY_CALIBRATION: u16 = 0x0006;
+/// Words of the bond of the `secure` feature, see `Bonder`.
+const KEY_BOND: [u16; BOND_WORDS] = [0x0010, 0x0011, 0x0012, 0x0013, 0x0014, 0x0015, 0x0016];
+/// Every key above, so that `PERSIST` holds one value of each.
+const PERSISTED_KEYS: [u16; 6 + BOND_WORDS] = [
+    KEY_BOOT_COUNT,
+    KEY_TRIM,
+    KEY_SETTLE_DELAY,
+    KEY_SAMPLE_INTERVAL,
+    KEY_NOTIFY_DELTA,
+    KEY_CALIBRATION,
+    KEY_BOND[0],
+    KEY_BOND[1],
+    KEY_BOND[2],
+    KEY_BOND[3],
+    KEY_BOND[4],
+    KEY_BOND[5],
+    KEY_BOND[6],
+];
+
+/// Persisted settings, `None` until the softdevice is running.
+///
+/// Hold the lock for the whole flash operation, so a reset never interrupts one.
+static STORAGE: Mutex<ThreadModeRawMutex, Option<Storage<Flash>>> = Mutex::new(None);
+
+/// Values waiting to be persisted by `persist_task`, GATT event handlers can't wait for the flash.
+///
+/// Sized for every key at once, as a restored config persists them all from a single handler.
+static PERSIST: Channel<ThreadModeRawMutex, (u16, u32), { PERSISTED_KEYS.len() }> = Channel::new();
+
+/// Writes the values queued in `PERSIST` to the storage.
+#[embassy_executor::task]
+async fn persist_task() -> ! {
+    loop {
+        let (key, value) = PERSIST.receive().await;
+        if let Some(storage) = STORAGE.lock().await.as_mut() {
+            if let Err(e) = storage.write(key, value).await {
+                warn!("Failed to persist {=u16:#x}: {:?}", key, e);
+            }
+        }
+    }
+}
+
+/// Queues a value to be persisted.
+fn persist(key: u16, value: u32) {
+    if PERSIST.try_send((key, value)).is_err() {
+        warn!("Persist queue is full, {=u16:#x} is not persisted", key);
+    }
+}
+
+/// Affine correction of the moisture channel, normalizing probes on cables of
+/// different lengths: `corrected = raw * gain_milli / 1000 + offset`.
+///
+/// Packed into a `u32` (and the trim characteristic) as little-endian
+/// `[offset: i16, gain_milli: u16]`.
+#[derive(Clone, Copy)]
+struct Trim {
+    offset: i16,
+    gain_milli: u16,
+}
+
+/// Current trim of the moisture channel, see `Trim::pack`.
+static TRIM: AtomicU32 = AtomicU32::new(Trim::NONE.pack());
+
+impl Trim {
+    const NONE: Trim = Trim { offset: 0, gain_milli: 1000 };
+
+    const fn pack(self) -> u32 {
+        self.offset as u16 as u32 | (self.gain_milli as u32) << 16
+    }
+
+    const fn unpack(packed: u32) -> Trim {
+        Trim {
+            offset: packed as u16 as i16,
+            gain_milli: (packed >> 16) as u16,
+        }
+    }
+
+    fn load() -> Trim {
+        Trim::unpack(TRIM.load(Ordering::Relaxed))
+    }
+
+    fn apply(self, raw: i16) -> i16 {
+        let corrected = raw as i32 * self.gain_milli as i32 / 1000 + self.offset as i32;
+        corrected.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+/// Restores the trim persisted by a previous `TrimWrite`, if any.
+async fn load_trim(storage: &mut Storage<Flash>) {
+    match storage.read(KEY_TRIM).await {
+        Ok(Some(packed)) => TRIM.store(packed, Ordering::Relaxed),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the trim: {:?}", e),
+    }
+    let trim = Trim::load();
+    info!("Trim: offset={=i16}, gain={=u16}/1000", trim.offset, trim.gain_milli);
+}
+
+/// Increments the persisted boot counter, returning the number of this boot (1 on the very first one).
+async fn count_boot(storage: &mut Storage<Flash>) -> u32 {
+    let boot_count = match storage.read(KEY_BOOT_COUNT).await {
+        Ok(count) => count.unwrap_or(0).wrapping_add(1),
+        Err(e) => {
+            warn!("Failed to read the boot count: {:?}", e);
+            return 0;
+        }
+    };
+    if let Err(e) = storage.write(KEY_BOOT_COUNT, boot_count).await {
+        warn!("Failed to persist the boot count: {:?}", e);
+    }
+    boot_count
+}
+
+/// Validates and executes a control command, returning the status to report back.
+fn handle_control_command(command: &[u8; 4]) -> u8 {
+    let (opcode, magic) = (command[0], &command[1..]);
+    if magic != CONTROL_MAGIC {
+        warn!("Control command {=u8:#x} rejected: bad magic", opcode);
+        return STATUS_BAD_MAGIC;
+    }
+
+    match opcode {
+        CMD_REBOOT => {
+            REBOOT.signal(());
+            STATUS_OK
+        }
+        CMD_CALIBRATION_BEGIN => {
+            CAPTURED_DRY_RAW_VALUE.store(NO_CALIBRATION_POINT, Ordering::Relaxed);
+            CAPTURED_WET_RAW_VALUE.store(NO_CALIBRATION_POINT, Ordering::Relaxed);
+            CALIBRATING.store(true, Ordering::Relaxed);
+            info!("Calibration started");
+            STATUS_OK
+        }
+        CMD_CALIBRATION_CAPTURE_DRY | CMD_CALIBRATION_CAPTURE_WET if !CALIBRATING.load(Ordering::Relaxed) => {
+            warn!("Calibration point capture outside of calibration");
+            STATUS_INVALID_STATE
+        }
+        CMD_CALIBRATION_CAPTURE_DRY => {
+            let raw_value = LAST_RAW_VALUE.load(Ordering::Relaxed);
+            CAPTURED_DRY_RAW_VALUE.store(raw_value, Ordering::Relaxed);
+            info!("Captured dry calibration point: {=i16}", raw_value);
+            STATUS_OK
+        }
+        CMD_CALIBRATION_CAPTURE_WET => {
+            let raw_value = LAST_RAW_VALUE.load(Ordering::Relaxed);
+            CAPTURED_WET_RAW_VALUE.store(raw_value, Ordering::Relaxed);
+            info!("Captured wet calibration point: {=i16}", raw_value);
+            STATUS_OK
+        }
+        CMD_CALIBRATION_END => end_calibration(),
+        CMD_TEST_PATTERN_START => {
+            TEST_PATTERN_NEXT.store(0, Ordering::Relaxed);
+            TEST_PATTERN.store(true, Ordering::Relaxed);
+            info!("Test pattern started");
+            STATUS_OK
+        }
+        CMD_TEST_PATTERN_STOP => {
+            TEST_PATTERN.store(false, Ordering::Relaxed);
+            info!("Test pattern stopped");
+            STATUS_OK
+        }
+        CMD_CONFIRM_UPDATE if !UPDATE_PENDING.load(Ordering::Relaxed) => {
+            warn!("No update to confirm");
+            STATUS_INVALID_STATE
+        }
+        CMD_CONFIRM_UPDATE => {
+            CONFIRM_UPDATE.signal(());
+            STATUS_OK
+        }
+        CMD_UPDATE_BEGIN | CMD_UPDATE_FINISH if !cfg!(feature = "ota") => {
+            warn!("Firmware updates need the `ota` feature");
+            STATUS_UNSUPPORTED
+        }
+        CMD_UPDATE_BEGIN => {
+            DFU_PAGE.lock(|page| *page.borrow_mut() = DfuPage::EMPTY);
+            DFU_ACTIVE.store(true, Ordering::Relaxed);
+            info!("Firmware upload started");
+            STATUS_OK
+        }
+        CMD_UPDATE_FINISH if !DFU_ACTIVE.load(Ordering::Relaxed) => {
+            warn!("No firmware upload to finish");
+            STATUS_INVALID_STATE
+        }
+        CMD_UPDATE_FINISH => {
+            DFU_ACTIVE.store(false, Ordering::Relaxed);
+            DFU_FINISH.signal(());
+            STATUS_OK
+        }
+        CMD_OPEN_PAIRING if !cfg!(feature = "secure") => {
+            warn!("Pairing needs the `secure` feature");
+            STATUS_UNSUPPORTED
+        }
+        #[cfg(feature = "secure")]
+        CMD_OPEN_PAIRING => {
+            open_pairing_window();
+            STATUS_OK
+        }
+        _ => {
+            warn!("Unsupported control command {=u8:#x}", opcode);
+            STATUS_UNSUPPORTED
+        }
+    }
+}
+
+#[cfg_eval]
 #[nrf_softdevice::gatt_service(uuid = "cafe")]
 struct SoilMoistureService {
-    #[characteristic(uuid = "babe", read, notify)]
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "babe", read, notify))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "babe", read, notify, security = "justworks"))]
     soil_moisture_level: i16,
+    /// Bit flags describing the sensor state, see `status_flags`.
+    #[characteristic(uuid = "bab1", read, notify)]
+    status: u8,
+    /// Correction applied to the raw moisture value, see `Trim`.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab2", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab2", read, write, security = "justworks"))]
+    trim: [u8; 4],
+    /// Microseconds between powering the probes up and sampling them, see `ProbePower`.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab5", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab5", read, write, security = "justworks"))]
+    settle_delay: u32,
+    /// Seconds between readings, within `SAMPLE_INTERVAL_SECS`.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab6", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab6", read, write, security = "justworks"))]
+    sample_interval: u16,
+    /// Smallest change of the raw reading that's notified, see `ChangeFilter`.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab7", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab7", read, write, security = "justworks"))]
+    notify_delta: u16,
+    /// Raw reading of the probe in completely dry soil, `NO_CALIBRATION_POINT` when unknown.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab9", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab9", read, write, security = "justworks"))]
+    dry_raw: i16,
+    /// Raw reading of the probe in completely wet soil, `NO_CALIBRATION_POINT` when unknown.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "baba", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "baba", read, write, security = "justworks"))]
+    wet_raw: i16,
+    /// Reading in percent between the calibration points, only notified once both are known.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "babb", read, notify))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "babb", read, notify, security = "justworks"))]
+    moisture_percent: u8,
+    /// Resolution of the raw readings in bits, so the client can tell their full scale.
+    #[characteristic(uuid = "babd", read)]
+    adc_resolution_bits: u8,
+    /// Any write samples and notifies the reading right away instead of at the next interval.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab8", write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab8", write, security = "justworks"))]
+    read_now: u8,
+    /// Standard deviation of the raw samples averaged into the last reading,
+    /// high values mean a loose contact or electrical noise.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab4", read, notify))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab4", read, notify, security = "justworks"))]
+    noise: u16,
+    /// `ALERT_*` bits of the alerts raised since the central last confirmed them.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "bab3", read, indicate))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "bab3", read, indicate, security = "justworks"))]
+    alert: u8,
+}
+
+/// Bits of the alert characteristic, each raised once when its condition starts.
+const ALERT_LOW_BATTERY: u8 = 1 << 0;
+const ALERT_PROBE_DROPPED: u8 = 1 << 1;
+
+/// Alerts not confirmed by a central yet.
+static PENDING_ALERTS: AtomicU8 = AtomicU8::new(0);
+/// Alerts of the indication in flight, cleared from `PENDING_ALERTS` once it's confirmed.
+static INDICATED_ALERTS: AtomicU8 = AtomicU8::new(0);
+
+fn raise_alert(alert: u8) {
+    PENDING_ALERTS.fetch_or(alert, Ordering::Relaxed);
+}
+
+/// Indicates the pending alerts, called on every sample until the central confirms them.
+///
+/// Unlike notifications, indications are acknowledged, so an alert lost on a
+/// flaky link (or raised while nobody listened) is indicated again.
+fn indicate_alerts(server: &Server, connection: &Connection) {
+    let alerts = PENDING_ALERTS.load(Ordering::Relaxed);
+    if alerts == 0 {
+        return;
+    }
+    unwrap!(server.sms.alert_set(&alerts));
+    // Fails while the previous indication is unconfirmed or indications are off, retried next time.
+    if server.sms.alert_indicate(connection, &alerts).is_ok() {
+        info!("Indicated alerts: {=u8:#04x}", alerts);
+        INDICATED_ALERTS.store(alerts, Ordering::Relaxed);
+    }
+}
+
+/// Clears the alerts of the indication the central just confirmed.
+fn on_alerts_confirmed() {
+    let alerts = INDICATED_ALERTS.swap(0, Ordering::Relaxed);
+    PENDING_ALERTS.fetch_and(!alerts, Ordering::Relaxed);
+    info!("Alerts confirmed: {=u8:#04x}", alerts);
+}
+
+/// Leaves the calibrating state, committing the captured points when both were captured.
+fn end_calibration() -> u8 {
+    if !CALIBRATING.swap(false, Ordering::Relaxed) {
+        return STATUS_INVALID_STATE;
+    }
+
+    let dry = CAPTURED_DRY_RAW_VALUE.load(Ordering::Relaxed);
+    let wet = CAPTURED_WET_RAW_VALUE.load(Ordering::Relaxed);
+    if dry == NO_CALIBRATION_POINT || wet == NO_CALIBRATION_POINT {
+        warn!("Calibration ended without both points, discarding it");
+        return STATUS_INVALID_STATE;
+    }
+
+    DRY_RAW_VALUE.store(dry, Ordering::Relaxed);
+    WET_RAW_VALUE.store(wet, Ordering::Relaxed);
+    persist_calibration();
+    info!("Calibration done: dry={=i16}, wet={=i16}", dry, wet);
+    STATUS_OK
+}
+
+/// Current value of the status characteristic.
+fn status_flags() -> u8 {
+    let mut flags = 0;
+    if CALIBRATING.load(Ordering::Relaxed) {
+        flags |= FLAG_CALIBRATING;
+    }
+    if BATTERY_LOW.load(Ordering::Relaxed) {
+        flags |= FLAG_LOW_BATTERY;
+    }
+    if TEST_PATTERN.load(Ordering::Relaxed) {
+        flags |= FLAG_TEST_PATTERN;
+    }
+    if PROBE_DROPPED.load(Ordering::Relaxed) {
+        flags |= FLAG_PROBE_DROPPED;
+    }
+    if UPDATE_PENDING.load(Ordering::Relaxed) {
+        flags |= FLAG_UPDATE_PENDING;
+    }
+    flags
+}
+
+/// Notifies the connected client of the current status flags.
+fn notify_status(server: &Server, connection: &Connection) {
+    let flags = status_flags();
+    if let Err(err) = server.sms.status_notify(connection, &flags) {
+        notify_failed("status", err);
+        unwrap!(server.sms.status_set(&flags));
+    }
+}
+
+/// Version of the configuration blob layout, bumped on every change of `config_blob`.
+const CONFIG_VERSION: u8 = 4;
+const CONFIG_SIZE: usize = 18;
+
+/// Snapshot of the device configuration, little endian:
+///
+/// | Offset | Type | Field                                                  |
+/// |--------|------|--------------------------------------------------------|
+/// | 0      | u8   | `CONFIG_VERSION`                                       |
+/// | 1      | u8   | reserved, 0                                            |
+/// | 2      | i16  | trim offset                                            |
+/// | 4      | u16  | trim gain in thousandths                               |
+/// | 6      | i16  | dry calibration point, `NO_CALIBRATION_POINT` if unset |
+/// | 8      | i16  | wet calibration point, `NO_CALIBRATION_POINT` if unset |
+/// | 10     | u32  | settle delay in microseconds                           |
+/// | 14     | u16  | sample interval in seconds                             |
+/// | 16     | u16  | notify delta in raw ADC counts                         |
+fn config_blob() -> [u8; CONFIG_SIZE] {
+    let trim = Trim::load();
+    let mut blob = [0u8; CONFIG_SIZE];
+    blob[0] = CONFIG_VERSION;
+    blob[2..4].copy_from_slice(&trim.offset.to_le_bytes());
+    blob[4..6].copy_from_slice(&trim.gain_milli.to_le_bytes());
+    blob[6..8].copy_from_slice(&DRY_RAW_VALUE.load(Ordering::Relaxed).to_le_bytes());
+    blob[8..10].copy_from_slice(&WET_RAW_VALUE.load(Ordering::Relaxed).to_le_bytes());
+    blob[10..14].copy_from_slice(&SETTLE_DELAY_US.load(Ordering::Relaxed).to_le_bytes());
+    blob[14..16].copy_from_slice(&SAMPLE_INTERVAL.load(Ordering::Relaxed).to_le_bytes());
+    blob[16..18].copy_from_slice(&NOTIFY_DELTA.load(Ordering::Relaxed).to_le_bytes());
+    blob
+}
+
+/// Applies a configuration blob written by the central, unless its version differs.
+fn restore_config(blob: &[u8; CONFIG_SIZE]) {
+    if blob[0] != CONFIG_VERSION {
+        warn!("Rejected configuration version {=u8}, expected {=u8}", blob[0], CONFIG_VERSION);
+        return;
+    }
+    let trim = Trim {
+        offset: i16::from_le_bytes([blob[2], blob[3]]),
+        gain_milli: u16::from_le_bytes([blob[4], blob[5]]),
+    };
+    let packed = trim.pack();
+    TRIM.store(packed, Ordering::Relaxed);
+    persist(KEY_TRIM, packed);
+    DRY_RAW_VALUE.store(i16::from_le_bytes([blob[6], blob[7]]), Ordering::Relaxed);
+    WET_RAW_VALUE.store(i16::from_le_bytes([blob[8], blob[9]]), Ordering::Relaxed);
+    persist_calibration();
+    let settle_delay_us = u32::from_le_bytes([blob[10], blob[11], blob[12], blob[13]]).min(MAX_SETTLE_DELAY_US);
+    SETTLE_DELAY_US.store(settle_delay_us, Ordering::Relaxed);
+    persist(KEY_SETTLE_DELAY, settle_delay_us);
+    let sample_interval = clamp_sample_interval(u16::from_le_bytes([blob[14], blob[15]]));
+    SAMPLE_INTERVAL.store(sample_interval, Ordering::Relaxed);
+    persist(KEY_SAMPLE_INTERVAL, sample_interval.into());
+    let notify_delta = u16::from_le_bytes([blob[16], blob[17]]);
+    NOTIFY_DELTA.store(notify_delta, Ordering::Relaxed);
+    persist(KEY_NOTIFY_DELTA, notify_delta.into());
+    info!("Configuration restored");
+}
+
+/// Refreshes the configuration characteristic after a setting changed.
+fn update_config(server: &Server) {
+    unwrap!(server.control.config_set(&config_blob()));
+}
+
+/// Device management commands, see `handle_control_command`.
+///
+/// Reading the characteristic returns `[opcode, status, 0, 0]` of the last handled command.
+#[cfg_eval]
+#[nrf_softdevice::gatt_service(uuid = "c0de")]
+struct ControlService {
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "c0d1", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "c0d1", read, write, security = "justworks"))]
+    command: [u8; 4],
+    /// The whole configuration in one read or write, see `config_blob`.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "c0d2", read, write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "c0d2", read, write, security = "justworks"))]
+    config: [u8; CONFIG_SIZE],
+}
+
+/// Upload of firmware images, see `receive_update_chunk`. Uploads are started
+/// and finished with control commands and need the `ota` feature.
+#[cfg_eval]
+#[nrf_softdevice::gatt_service(uuid = "df00")]
+struct DfuService {
+    /// `[offset: u32, data]` chunks of the image, in order, 4 + `DFU_CHUNK_SIZE` bytes at most.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "df01", write))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "df01", write, security = "justworks"))]
+    data: heapless::Vec<u8, 244>,
+    /// Bytes of the image written to the DFU partition so far.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "df02", read))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "df02", read, security = "justworks"))]
+    progress: u32,
+}
+
+/// Diagnostics for tracking the device's reliability in the field.
+#[nrf_softdevice::gatt_service(uuid = "d1a6")]
+struct DiagnosticsService {
+    /// Number of times the device booted, 0 when the storage is unreadable.
+    #[characteristic(uuid = "d1a0", read)]
+    boot_count: u32,
+    /// Bytes of stack never used since boot, updated on every sample.
+    #[characteristic(uuid = "d1a1", read)]
+    free_stack: u32,
+    /// Version of the running firmware, NUL padded, see `firmware_version`.
+    #[characteristic(uuid = "d1a2", read)]
+    firmware_version: [u8; 16],
+    /// Supply voltage in millivolts, updated on every sample.
+    #[characteristic(uuid = "d1a3", read)]
+    supply_voltage: u16,
+}
+
+/// The crate version, NUL padded to fit the firmware version characteristic.
+fn firmware_version() -> [u8; 16] {
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let mut padded = [0u8; 16];
+    let len = version.len().min(padded.len());
+    padded[..len].copy_from_slice(&version[..len]);
+    padded
+}
+
+/// The reading in the standard Environmental Sensing Service, for generic BLE
+/// apps like nRF Connect. The service has no soil moisture characteristic, so
+/// the moisture is exposed as a Humidity, see `moisture_milli`.
+#[cfg_eval]
+#[nrf_softdevice::gatt_service(uuid = "181a")]
+struct EnvironmentalSensingService {
+    /// Moisture in hundredths of a percent.
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "2a6f", read, notify))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "2a6f", read, notify, security = "justworks"))]
+    humidity: u16,
+    /// Temperature of the nRF52 die in hundredths of a degree Celsius, sampled
+    /// with every reading, for the client to compensate the moisture's drift.
+    #[cfg(feature = "die-temperature")]
+    #[cfg_attr(not(feature = "secure"), characteristic(uuid = "2a6e", read, notify))]
+    #[cfg_attr(feature = "secure", characteristic(uuid = "2a6e", read, notify, security = "justworks"))]
+    temperature: i16,
+}
+
+/// Standard Device Information Service, for fleet management tools.
+#[nrf_softdevice::gatt_service(uuid = "180a")]
+struct DeviceInformationService {
+    /// The crate version, see `firmware_version`.
+    #[characteristic(uuid = "2a26", read)]
+    firmware_revision: heapless::Vec<u8, 16>,
+    #[characteristic(uuid = "2a27", read)]
+    hardware_revision: heapless::Vec<u8, 16>,
+}
+
+/// Board the firmware runs on, reported as the hardware revision.
+const HARDWARE_REVISION: &str = "micro:bit v2";
+
+/// `value` as a string characteristic value, cut to `N` bytes.
+fn string_value<const N: usize>(value: &str) -> heapless::Vec<u8, N> {
+    let bytes = value.as_bytes();
+    unwrap!(heapless::Vec::from_slice(&bytes[..bytes.len().min(N)]))
 }
 
 #[nrf_softdevice::gatt_server]
 struct Server {
     sms: SoilMoistureService,
+    control: ControlService,
+    dfu: DfuService,
+    diagnostics: DiagnosticsService,
+    ess: EnvironmentalSensingService,
+    dis: DeviceInformationService,
+}
+
+/// Handles the events of the GATT server.
+fn on_server_event(server: &Server, conn: &Connection, e: ServerEvent) {
+    match e {
+        ServerEvent::Sms(e) => match e {
+            SoilMoistureServiceEvent::SoilMoistureLevelCccdWrite { notifications } => {
+                info!("Soil moisture notifications: {}", notifications);
+                NOTIFICATIONS_ENABLED.store(notifications, Ordering::Relaxed);
+                NOTIFICATIONS_CHANGED.signal(());
+            }
+            SoilMoistureServiceEvent::StatusCccdWrite { notifications } => {
+                info!("Status notifications: {}", notifications)
+            }
+            SoilMoistureServiceEvent::NoiseCccdWrite { notifications } => {
+                info!("Noise notifications: {}", notifications)
+            }
+            SoilMoistureServiceEvent::AlertCccdWrite { indications } => {
+                info!("Alert indications: {}", indications)
+            }
+            SoilMoistureServiceEvent::TrimWrite(trim) => {
+                let packed = u32::from_le_bytes(trim);
+                let trim = Trim::unpack(packed);
+                info!("New trim: offset={=i16}, gain={=u16}/1000", trim.offset, trim.gain_milli);
+                TRIM.store(packed, Ordering::Relaxed);
+                persist(KEY_TRIM, packed);
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::SettleDelayWrite(delay_us) => {
+                let delay_us = delay_us.min(MAX_SETTLE_DELAY_US);
+                info!("New settle delay: {=u32}us", delay_us);
+                SETTLE_DELAY_US.store(delay_us, Ordering::Relaxed);
+                persist(KEY_SETTLE_DELAY, delay_us);
+                unwrap!(server.sms.settle_delay_set(&delay_us));
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::SampleIntervalWrite(secs) => {
+                let secs = clamp_sample_interval(secs);
+                info!("New sample interval: {=u16}s", secs);
+                SAMPLE_INTERVAL.store(secs, Ordering::Relaxed);
+                persist(KEY_SAMPLE_INTERVAL, secs.into());
+                unwrap!(server.sms.sample_interval_set(&secs));
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::NotifyDeltaWrite(delta) => {
+                info!("New notify delta: {=u16}", delta);
+                NOTIFY_DELTA.store(delta, Ordering::Relaxed);
+                persist(KEY_NOTIFY_DELTA, delta.into());
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::ReadNowWrite(_) => READ_NOW.signal(()),
+            SoilMoistureServiceEvent::DryRawWrite(raw_value) => {
+                info!("New dry calibration point: {=i16}", raw_value);
+                DRY_RAW_VALUE.store(raw_value, Ordering::Relaxed);
+                persist_calibration();
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::WetRawWrite(raw_value) => {
+                info!("New wet calibration point: {=i16}", raw_value);
+                WET_RAW_VALUE.store(raw_value, Ordering::Relaxed);
+                persist_calibration();
+                update_config(server);
+            }
+            SoilMoistureServiceEvent::MoisturePercentCccdWrite { notifications } => {
+                info!("Moisture percent notifications: {}", notifications);
+                PERCENT_NOTIFICATIONS_ENABLED.store(notifications, Ordering::Relaxed);
+                NOTIFICATIONS_CHANGED.signal(());
+            }
+        },
+        ServerEvent::Control(e) => match e {
+            ControlServiceEvent::CommandWrite(command) => {
+                let was_calibrating = CALIBRATING.load(Ordering::Relaxed);
+                let status = handle_control_command(&command);
+                if CALIBRATING.load(Ordering::Relaxed) != was_calibrating {
+                    request_conn_params(conn);
+                }
+                unwrap!(server.control.command_set(&[command[0], status, 0, 0]));
+                notify_status(server, conn);
+                // Ending a calibration changes the configuration.
+                update_config(server);
+                update_calibration(server);
+            }
+            ControlServiceEvent::ConfigWrite(blob) => {
+                restore_config(&blob);
+                // Also reverts a rejected blob, so reading back tells whether it was accepted.
+                update_config(server);
+                unwrap!(server.sms.trim_set(&TRIM.load(Ordering::Relaxed).to_le_bytes()));
+                unwrap!(server.sms.settle_delay_set(&SETTLE_DELAY_US.load(Ordering::Relaxed)));
+                unwrap!(server.sms.sample_interval_set(&SAMPLE_INTERVAL.load(Ordering::Relaxed)));
+                unwrap!(server.sms.notify_delta_set(&NOTIFY_DELTA.load(Ordering::Relaxed)));
+                update_calibration(server);
+            }
+        },
+        ServerEvent::Dfu(e) => match e {
+            DfuServiceEvent::DataWrite(chunk) => receive_update_chunk(&chunk),
+        },
+        ServerEvent::Diagnostics(e) => match e {},
+        ServerEvent::Dis(e) => match e {},
+        ServerEvent::Ess(e) => match e {
+            EnvironmentalSensingServiceEvent::HumidityCccdWrite { notifications } => {
+                info!("Humidity notifications: {}", notifications);
+                ESS_NOTIFICATIONS_ENABLED.store(notifications, Ordering::Relaxed);
+                NOTIFICATIONS_CHANGED.signal(());
+            }
+            // Notified along with the reading, so there's nothing to start.
+            #[cfg(feature = "die-temperature")]
+            EnvironmentalSensingServiceEvent::TemperatureCccdWrite { notifications } => {
+                info!("Temperature notifications: {}", notifications);
+            }
+        },
+    }
+}
+
+/// Whether the connection outlives a GATT server error.
+///
+/// Disconnection is fatal, anything else (e.g. the softdevice being
+/// momentarily out of resources) is fine as long as the link is still up.
+fn is_recoverable(conn: &Connection, error: &RunError) -> bool {
+    match error {
+        RunError::Disconnected => false,
+        RunError::Raw(_) => conn.handle().is_some(),
+    }
+}
+
+/// `Server` plus handling of indication confirmations, which the generated server ignores.
+struct ConfirmingServer<'a>(&'a Server);
+
+impl gatt_server::Server for ConfirmingServer<'_> {
+    type Event = ServerEvent;
+
+    fn on_write(&self, conn: &Connection, handle: u16, op: WriteOp, offset: usize, data: &[u8]) -> Option<ServerEvent> {
+        gatt_server::Server::on_write(self.0, conn, handle, op, offset, data)
+    }
+
+    fn on_indicate_confirm(&self, _conn: &Connection, handle: u16) -> Option<ServerEvent> {
+        if handle == self.0.sms.alert_value_handle {
+            on_alerts_confirmed();
+        }
+        None
+    }
+}
+
+/// Runs the GATT server until the connection is gone, restarting it after
+/// recoverable errors instead of tearing the connection down.
+async fn serve_gatt(server: &Server, conn: &Connection) -> RunError {
+    loop {
+        let confirming = ConfirmingServer(server);
+        let error = match gatt_server::run(conn, &confirming, |e| on_server_event(server, conn, e)).await {
+            Ok(()) => RunError::Disconnected,
+            Err(error) => error,
+        };
+        if !is_recoverable(conn, &error) {
+            return error;
+        }
+        warn!("gatt_server run failed with {:?}, keeping the connection", error);
+    }
+}
+
+/// Size in bytes of the softdevice's GATT attribute table.
+///
+/// The table holds every attribute of the GATT server: the softdevice's own GAP
+/// and GATT services plus ours. Each attribute takes roughly 20 bytes plus its
+/// value (values are stored in the table, see `BLE_GATTS_VLOC_STACK`), and every
+/// characteristic is 2 attributes, 3 with notifications or indications. Today's
+/// services need about 1600 bytes, the 244 byte DFU data characteristic alone
+/// takes nearly 300, so the table is 640 bytes above the softdevice's default
+/// of 1408, leaving room for a few more characteristics.
+///
+/// The table lives in the softdevice's RAM, so after changing this the RAM
+/// origin in `memory.x` must follow, the softdevice logs the required value on
+/// `Softdevice::enable`. A table that's too small makes `Server::new` fail.
+const ATTR_TAB_SIZE: u32 = 2048;
+
+/// Shortest connection interval the sensor accepts, in 1.25ms units (100ms).
+///
+/// Every connection event wakes the radio up, so a gateway asking for e.g. a
+/// 7.5ms interval multiplies the idle power draw while readings come in only
+/// every 10 seconds anyway. The central has the last word on connection
+/// parameters, so the floor is enforced by asking it for `PREFERRED_CONN_PARAMS`
+/// again whenever it picked a shorter interval.
+const MIN_CONN_INTERVAL: u16 = 80;
+
+/// Connection parameters the sensor prefers when deployed, also published as its PPCP.
+///
+/// The supervision timeout must stay above `(1 + slave_latency) * max_conn_interval * 2`,
+/// or the softdevice rejects the parameters: with a 200ms interval and 4s timeout
+/// a slave latency of up to 9 is possible. Latency stays 0 so notifications and
+/// indications go out on the next connection event, and a lost link is still
+/// detected within 4s.
+const PREFERRED_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: MIN_CONN_INTERVAL,
+    max_conn_interval: 2 * MIN_CONN_INTERVAL,
+    slave_latency: 0,
+    // 10ms units.
+    conn_sup_timeout: 400,
+};
+
+/// Supervision timeout of `PREFERRED_CONN_PARAMS`, after which a silent link counts as lost.
+const SUPERVISION_TIMEOUT: Duration = Duration::from_millis(PREFERRED_CONN_PARAMS.conn_sup_timeout as u64 * 10);
+
+/// How long a connected client may leave the reading's notifications disabled
+/// before the sensor disconnects it and advertises again.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(SUPERVISION_TIMEOUT.as_secs() + ADVERTISING_REFRESH.as_secs());
+
+/// Connection parameters asked for while calibrating, a 30-50ms interval so
+/// the readings and captures of the calibration tool respond quickly. The
+/// floor of `MIN_CONN_INTERVAL` isn't enforced meanwhile.
+const CALIBRATION_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: 24,
+    max_conn_interval: 40,
+    slave_latency: 0,
+    // 10ms units.
+    conn_sup_timeout: 400,
+};
+
+/// Connection parameters wanted right now: fast while calibrating, power saving otherwise.
+fn wanted_conn_params() -> raw::ble_gap_conn_params_t {
+    if CALIBRATING.load(Ordering::Relaxed) {
+        CALIBRATION_CONN_PARAMS
+    } else {
+        PREFERRED_CONN_PARAMS
+    }
+}
+
+/// Asks the central for the `wanted_conn_params`, after connecting and whenever
+/// a calibration begins or ends.
+fn request_conn_params(conn: &Connection) {
+    let params = wanted_conn_params();
+    info!(
+        "Requesting a connection interval of {=u16}-{=u16} (1.25ms units)",
+        params.min_conn_interval, params.max_conn_interval
+    );
+    if let Err(e) = conn.set_conn_params(params) {
+        warn!("Failed to request connection parameters: {:?}", e);
+    }
+}
+
+/// Asks the central for `PREFERRED_CONN_PARAMS` when it picked an interval below `MIN_CONN_INTERVAL`.
+fn enforce_min_conn_interval(conn: &Connection) {
+    let params = conn.conn_params();
+    if CALIBRATING.load(Ordering::Relaxed) || params.max_conn_interval >= MIN_CONN_INTERVAL {
+        return;
+    }
+    warn!(
+        "Connection interval {=u16} below {=u16} (1.25ms units), renegotiating",
+        params.max_conn_interval, MIN_CONN_INTERVAL
+    );
+    if let Err(e) = conn.set_conn_params(PREFERRED_CONN_PARAMS) {
+        warn!("Failed to renegotiate connection parameters: {:?}", e);
+    }
+}
+
+/// Keeps the bond of the one central allowed to read the sensor with the `secure` feature.
+///
+/// Pairing is Just Works, as the board has no way to enter or show a passkey
+/// beyond the LED matrix: it encrypts the link against eavesdroppers but doesn't
+/// authenticate the central. So only the first central bonds, any other only
+/// within `PAIRING_WINDOW` of it being opened, by the bonded central with
+/// `CMD_OPEN_PAIRING` or by holding button A while the board boots, and then
+/// replaces the bonded one. The bond is persisted and survives a reset.
+#[cfg(feature = "secure")]
+struct Bonder {
+    peer: core::cell::Cell<Option<(MasterId, EncryptionInfo)>>,
+}
+
+#[cfg(feature = "secure")]
+static BONDER: Bonder = Bonder {
+    peer: core::cell::Cell::new(None),
+};
+
+// The executor runs on a single core in thread mode, the softdevice only calls the handler from there.
+#[cfg(feature = "secure")]
+unsafe impl Sync for Bonder {}
+
+/// How long a new central may bond once pairing was opened, see `Bonder`.
+#[cfg(feature = "secure")]
+const PAIRING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Uptime in seconds until which a new central may bond, 0 when pairing is closed.
+#[cfg(feature = "secure")]
+static PAIRING_UNTIL: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(feature = "secure")]
+fn open_pairing_window() {
+    info!("Pairing open for {=u64} seconds", PAIRING_WINDOW.as_secs());
+    PAIRING_UNTIL.store((Instant::now() + PAIRING_WINDOW).as_secs() as u32, Ordering::Relaxed);
+}
+
+#[cfg(feature = "secure")]
+fn pairing_open() -> bool {
+    Instant::now().as_secs() < PAIRING_UNTIL.load(Ordering::Relaxed) as u64
+}
+
+/// Words a bond is persisted in, see `pack_bond`.
+const BOND_WORDS: usize = 7;
+
+/// Packs a bond as `[ediv: u16, rand: [u8; 8], ltk: [u8; 16], flags: u8, checksum: u8]`,
+/// the checksum being the XOR of the bytes before it, so a bond only partly
+/// persisted when a reset hit isn't loaded.
+#[cfg(feature = "secure")]
+fn pack_bond(master_id: &MasterId, key: &EncryptionInfo) -> [u32; BOND_WORDS] {
+    let mut bytes = [0u8; BOND_WORDS * 4];
+    bytes[0..2].copy_from_slice(&master_id.ediv.to_le_bytes());
+    bytes[2..10].copy_from_slice(&master_id.rand);
+    bytes[10..26].copy_from_slice(&key.ltk);
+    bytes[26] = key.flags;
+    bytes[27] = bytes[..27].iter().fold(0, |checksum, byte| checksum ^ byte);
+    let mut words = [0u32; BOND_WORDS];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    words
+}
+
+/// Unpacks a bond packed by `pack_bond`, `None` when its checksum doesn't match.
+#[cfg(feature = "secure")]
+fn unpack_bond(words: &[u32; BOND_WORDS]) -> Option<(MasterId, EncryptionInfo)> {
+    let mut bytes = [0u8; BOND_WORDS * 4];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    if bytes[..27].iter().fold(0, |checksum, byte| checksum ^ byte) != bytes[27] {
+        return None;
+    }
+    let mut rand = [0u8; 8];
+    rand.copy_from_slice(&bytes[2..10]);
+    let mut ltk = [0u8; 16];
+    ltk.copy_from_slice(&bytes[10..26]);
+    let master_id = MasterId {
+        ediv: u16::from_le_bytes([bytes[0], bytes[1]]),
+        rand,
+    };
+    Some((master_id, EncryptionInfo { ltk, flags: bytes[26] }))
+}
+
+/// Restores the bond persisted by `Bonder::on_bonded`, if any.
+#[cfg(feature = "secure")]
+async fn load_bond(storage: &mut Storage<Flash>) {
+    let mut words = [0u32; BOND_WORDS];
+    for (word, key) in words.iter_mut().zip(KEY_BOND) {
+        match storage.read(key).await {
+            Ok(Some(value)) => *word = value,
+            Ok(None) => {
+                info!("No central bonded yet");
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read the bond: {:?}", e);
+                return;
+            }
+        }
+    }
+    match unpack_bond(&words) {
+        Some(bond) => {
+            info!("Restored the bond");
+            BONDER.peer.set(Some(bond));
+        }
+        None => warn!("Persisted bond is corrupted, pair again"),
+    }
+}
+
+#[cfg(feature = "secure")]
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::None
+    }
+
+    fn can_bond(&self, conn: &Connection) -> bool {
+        let allowed = self.peer.get().is_none() || pairing_open();
+        if !allowed {
+            // Pairing without bonding would still encrypt the link, so drop the central instead.
+            warn!("Rejected pairing outside of the pairing window");
+            let _ = conn.disconnect();
+        }
+        allowed
+    }
+
+    fn on_bonded(&self, _conn: &Connection, master_id: MasterId, key: EncryptionInfo, _peer_id: IdentityKey) {
+        info!("Bonded with a new central");
+        // The window lets a single central in.
+        PAIRING_UNTIL.store(0, Ordering::Relaxed);
+        for (storage_key, word) in KEY_BOND.into_iter().zip(pack_bond(&master_id, &key)) {
+            persist(storage_key, word);
+        }
+        self.peer.set(Some((master_id, key)));
+    }
+
+    fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.peer
+            .get()
+            .and_then(|(bonded_id, key)| (bonded_id == master_id).then_some(key))
+    }
+}
+
+/// Name the sensor advertises, `MicroBit` unless set with the `SENSOR_NAME`
+/// environment variable at build time, e.g. `SENSOR_NAME=MicroBit-Basil cargo build`.
+const DEVICE_NAME: &str = match option_env!("SENSOR_NAME") {
+    Some(name) => name,
+    None => "MicroBit",
+};
+/// Longest name fitting the 31 byte advertising data next to the flags, service and manufacturer data.
+const MAX_DEVICE_NAME_LEN: usize = 16;
+const _: () = assert!(
+    !DEVICE_NAME.is_empty() && DEVICE_NAME.len() <= MAX_DEVICE_NAME_LEN,
+    "SENSOR_NAME must be 1 to 16 bytes long"
+);
+
+/// Company identifier of the manufacturer specific advertising data (reserved for testing).
+const MANUFACTURER_ID: u16 = 0xffff;
+/// How often advertising is restarted to refresh the advertised battery state.
+const ADVERTISING_REFRESH: Duration = Duration::from_secs(60);
+/// How long the sensor advertises at the default rate after booting or losing
+/// its connection, so a gateway picks it up again quickly.
+const FAST_ADVERTISING_DURATION: Duration = ADVERTISING_REFRESH;
+/// Advertising interval once nobody connected for `FAST_ADVERTISING_DURATION`,
+/// in 0.625ms units (1022.5ms): about a quarter of the radio's default wake-ups
+/// while still caught by the exporter's 2 second scans.
+const SLOW_ADVERTISING_INTERVAL: u32 = 1636;
+
+/// Builds the advertising data, carrying the status flags as manufacturer specific data
+/// so scanners can spot low-battery sensors without connecting.
+#[rustfmt::skip]
+fn advertising_data(flags: u8) -> heapless::Vec<u8, 31> {
+    let [id_lo, id_hi] = MANUFACTURER_ID.to_le_bytes();
+    let mut data = heapless::Vec::new();
+    unwrap!(data.extend_from_slice(&[
+        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+        0x03, 0x03, 0x1a, 0x18,
+        DEVICE_NAME.len() as u8 + 1, 0x09,
+    ]));
+    unwrap!(data.extend_from_slice(DEVICE_NAME.as_bytes()));
+    unwrap!(data.extend_from_slice(&[
+        0x04, 0xff, id_lo, id_hi, flags,
+    ]));
+    data
 }
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    info!("Hello World!");
+    stack::paint();
+    info!("Hello World! I'm {=str}", DEVICE_NAME);
 
     // First we get the peripherals access crate.
     let mut config = embassy_nrf::config::Config::default();
     config.gpiote_interrupt_priority = interrupt::Priority::P2;
     config.time_interrupt_priority = interrupt::Priority::P2;
     let p = embassy_nrf::init(config);
+    // Booting, including opening the storage, takes well below the watchdog timeout.
+    expect_progress(Duration::from_secs(0));
+    unwrap!(spawner.spawn(watchdog_task(start_watchdog(p.WDT))));
 
-    // Then we initialize the ADC. We are only using one channel in this example.
-    let adc_pin = p.P0_03.degrade_saadc();
-    let mut saadc = init_adc(adc_pin, p.SAADC);
-    // Indicated: wait for ADC calibration.
-    saadc.calibrate().await;
+    // Then we initialize the ADC on the moisture probes.
+    #[cfg(not(feature = "dual-probe"))]
+    let probe_pins = [p.P0_03.degrade_saadc()];
+    #[cfg(feature = "dual-probe")]
+    let probe_pins = [p.P0_03.degrade_saadc(), p.P0_04.degrade_saadc()];
+    let mut saadc = init_adc(probe_pins, p.SAADC);
+    // Indicated: wait for ADC calibration, repeated every `ADC_CALIBRATION_INTERVAL` while sampling.
+    calibrate_adc(&mut saadc).await;
+
+    let mut display = LedMatrix::new(
+        [p.P0_21.degrade(), p.P0_22.degrade(), p.P0_15.degrade(), p.P0_24.degrade(), p.P0_19.degrade()],
+        [p.P0_28.degrade(), p.P0_11.degrade(), p.P0_31.degrade(), p.P1_05.degrade(), p.P0_30.degrade()],
+    );
+
+    #[cfg(feature = "probe-power")]
+    let mut probe_power = ProbePower::new(Some(p.P1_02.degrade()));
+    #[cfg(not(feature = "probe-power"))]
+    let mut probe_power = ProbePower::new(None);
 
     let config = nrf_softdevice::Config {
         clock: Some(raw::nrf_clock_lf_cfg_t {
@@ -99,7 +2088,7 @@ async fn main(spawner: Spawner) {
         }),
         conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 256 }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
-            attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT.into(),
+            attr_tab_size: ATTR_TAB_SIZE,
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: raw::BLE_GAP_ADV_SET_COUNT_DEFAULT as u8,
@@ -109,9 +2098,9 @@ async fn main(spawner: Spawner) {
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
         }),
         gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
-            p_value: b"MicroBit" as *const u8 as _,
-            current_len: 8,
-            max_len: 8,
+            p_value: DEVICE_NAME.as_ptr() as _,
+            current_len: DEVICE_NAME.len() as u16,
+            max_len: DEVICE_NAME.len() as u16,
             write_perm: unsafe { mem::zeroed() },
             _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(raw::BLE_GATTS_VLOC_STACK as u8),
         }),
@@ -119,27 +2108,101 @@ async fn main(spawner: Spawner) {
     };
 
     let sd = Softdevice::enable(&config);
-    let server = unwrap!(Server::new(sd));
+    let server = match Server::new(sd) {
+        Ok(server) => server,
+        Err(RegisterError::Raw(RawError::NoMem)) => {
+            defmt::panic!("GATT attribute table is full, increase ATTR_TAB_SIZE ({=u32} bytes)", ATTR_TAB_SIZE)
+        }
+        Err(e) => defmt::panic!("Failed to register the GATT server: {:?}", e),
+    };
 
+    // Centrals that honor the peripheral preferred connection parameters won't go below the floor in the first place.
+    let ret = unsafe { raw::sd_ble_gap_ppcp_set(&PREFERRED_CONN_PARAMS) };
+    if ret != raw::NRF_SUCCESS {
+        warn!("Failed to set the preferred connection parameters: {=u32}", ret);
+    }
+
+    seed_jitter(sd);
     unwrap!(spawner.spawn(softdevice_task(sd)));
+    unwrap!(spawner.spawn(reboot_task()));
+    unwrap!(spawner.spawn(persist_task()));
+
+    // Flash operations complete through softdevice events, so it must be running by now.
+    let mut storage = unwrap!(Storage::new(Flash::take(sd), STORAGE_START).await);
+    let boot_count = count_boot(&mut storage).await;
+    info!("Boot count: {=u32}", boot_count);
+    unwrap!(server.diagnostics.boot_count_set(&boot_count));
+    unwrap!(server.diagnostics.firmware_version_set(&firmware_version()));
+    unwrap!(server.dis.firmware_revision_set(&string_value(env!("CARGO_PKG_VERSION"))));
+    unwrap!(server.dis.hardware_revision_set(&string_value(HARDWARE_REVISION)));
+    unwrap!(server.sms.adc_resolution_bits_set(&ADC_RESOLUTION_BITS));
+    load_trim(&mut storage).await;
+    unwrap!(server.sms.trim_set(&TRIM.load(Ordering::Relaxed).to_le_bytes()));
+    load_settle_delay(&mut storage).await;
+    unwrap!(server.sms.settle_delay_set(&SETTLE_DELAY_US.load(Ordering::Relaxed)));
+    load_sample_interval(&mut storage).await;
+    unwrap!(server.sms.sample_interval_set(&SAMPLE_INTERVAL.load(Ordering::Relaxed)));
+    load_notify_delta(&mut storage).await;
+    unwrap!(server.sms.notify_delta_set(&NOTIFY_DELTA.load(Ordering::Relaxed)));
+    load_calibration(&mut storage).await;
+    update_calibration(&server);
+    #[cfg(feature = "secure")]
+    {
+        load_bond(&mut storage).await;
+        // Lets a new central bond, e.g. when the bonded one was lost.
+        if embassy_nrf::gpio::Input::new(p.P0_14, embassy_nrf::gpio::Pull::Up).is_low() {
+            open_pairing_window();
+        }
+    }
+    update_config(&server);
+    #[cfg(feature = "ota")]
+    if is_trial_boot(&mut storage).await {
+        warn!("Running a new image, confirm it within {=u64}s or it's rolled back", UPDATE_CONFIRM_TIMEOUT.as_secs());
+        UPDATE_PENDING.store(true, Ordering::Relaxed);
+        unwrap!(spawner.spawn(update_guard_task()));
+    }
+    *STORAGE.lock().await = Some(storage);
 
-    #[rustfmt::skip]
-    let adv_data = &[
-        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        0x03, 0x03, 0x1a, 0x18,
-        0x09, 0x09, b'M', b'i', b'c', b'r', b'o', b'B', b'i', b't',
-    ];
     #[rustfmt::skip]
     let scan_data = &[
         0x03, 0x03, 0x1a, 0x18,
     ];
 
+    // Since when no client is connected.
+    let mut idle_since = Instant::now();
     loop {
-        let config = peripheral::Config::default();
+        // The executor sleeps the core (WFE) whenever no task is ready, so
+        // between samples and advertising events only the softdevice wakes it.
+        let mut config = peripheral::Config::default();
+        if idle_since.elapsed() >= FAST_ADVERTISING_DURATION {
+            config.interval = SLOW_ADVERTISING_INTERVAL;
+        }
 
+        // Nobody's looking at the readings while disconnected, a single LED tells we're advertising.
+        display.show_advertising();
+        let vdd_raw_value = sample_all(&mut saadc).await[VDD_CHANNEL];
+        unwrap!(server.diagnostics.supply_voltage_set(&vdd_raw_to_mv(vdd_raw_value)));
+        if check_battery(vdd_raw_value) {
+            display.blink_low_battery().await;
+        }
+        let adv_data = &advertising_data(status_flags())[..];
         let adv = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
-        let conn = unwrap!(peripheral::advertise_connectable(sd, adv, &config).await);
+        #[cfg(not(feature = "secure"))]
+        let adv_fut = peripheral::advertise_connectable(sd, adv, &config);
+        #[cfg(feature = "secure")]
+        let adv_fut = peripheral::advertise_pairable(sd, adv, &config, &BONDER);
+        expect_progress(ADVERTISING_REFRESH);
+        let refresh_fut = Timer::after(ADVERTISING_REFRESH);
+        pin_mut!(adv_fut);
+        pin_mut!(refresh_fut);
+
+        // Restart advertising now and then, so it reflects the current battery state.
+        let conn = match select(adv_fut, refresh_fut).await {
+            Either::Left((conn, _)) => unwrap!(conn),
+            Either::Right(_) => continue,
+        };
         info!("advertising done! I have a connection.");
+        request_conn_params(&conn);
 
         // We have a GATT connection. Now we will create two futures:
         //  - An infinite loop gathering data from the ADC and notifying the clients.
@@ -147,29 +2210,26 @@ async fn main(spawner: Spawner) {
         //
         // Event enums (ServerEvent's) are generated by nrf_softdevice::gatt_server
         // proc macro when applied to the Server struct above
-        let adc_fut = notify_adc_value(&mut saadc, &server, &conn);
-        let gatt_fut = gatt_server::run(&conn, &server, |e| match e {
-            ServerEvent::Sms(e) => match e {
-                SoilMoistureServiceEvent::SoilMoistureLevelCccdWrite { notifications } => {
-                    info!("Soil moisture notifications: {}", notifications)
-                }
-            },
-        });
+        let adc_fut = notify_adc_value(&mut saadc, &mut probe_power, &mut display, &server, &conn);
+        let dfu_fut = write_update(&server);
+        let gatt_fut = serve_gatt(&server, &conn);
 
         pin_mut!(adc_fut);
+        pin_mut!(dfu_fut);
         pin_mut!(gatt_fut);
 
         // We are using "select" to wait for either one of the futures to complete.
         // There are some advantages to this approach:
         //  - we only gather data when a client is connected, therefore saving some power.
         //  - when the GATT server finishes operating, our ADC future is also automatically aborted.
-        let _ = match select(adc_fut, gatt_fut).await {
+        let _ = match select(select(adc_fut, dfu_fut), gatt_fut).await {
             Either::Left((_, _)) => {
                 info!("ADC encountered an error and stopped!")
             }
             Either::Right((e, _)) => {
-                info!("gatt_server run exited with error: {:?}", e);
+                info!("gatt_server run exited with error: {:?}, advertising again", e);
             }
         };
+        idle_since = Instant::now();
     }
 }
@@ -0,0 +1,41 @@
+//! Stack high-water mark estimation: the unused stack is painted with a known
+//! pattern at startup, the pattern left untouched tells how deep the stack got.
+
+use core::ptr::{addr_of, addr_of_mut};
+
+const PAINT: u32 = 0xaaaa_aaaa;
+/// Words right below the stack pointer left unpainted, room for `paint`'s own frame.
+const PAINT_MARGIN_WORDS: usize = 64;
+
+extern "C" {
+    /// End of the static data placed by `cortex-m-rt`, the stack grows down towards it.
+    static mut __sheap: u32;
+}
+
+/// Fills the stack below the current stack pointer with the paint pattern.
+///
+/// Call it as early as possible, only the stack deeper than at the time of
+/// the call is tracked.
+pub fn paint() {
+    unsafe {
+        let mut word = addr_of_mut!(__sheap);
+        let top = (cortex_m::register::msp::read() as *mut u32).sub(PAINT_MARGIN_WORDS);
+        while word < top {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// Bytes of stack never used since `paint`, the headroom left at the stack's high-water mark.
+pub fn free_bytes() -> u32 {
+    unsafe {
+        let bottom = addr_of!(__sheap);
+        let top = cortex_m::register::msp::read() as *const u32;
+        let mut word = bottom;
+        while word < top && word.read_volatile() == PAINT {
+            word = word.add(1);
+        }
+        word as u32 - bottom as u32
+    }
+}
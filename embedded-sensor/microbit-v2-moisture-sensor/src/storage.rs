@@ -0,0 +1,174 @@
+//! Wear-leveled storage of a few `u32` values in a dedicated flash region.
+//!
+//! The region is made of two pages used in turns as an append-only log of
+//! 8 byte records `[key, !key, value]`. Writing a value appends a record, the
+//! latest record of a key wins. Once the active page is full, the latest value
+//! of every key is copied over to the other page, which then becomes active.
+//! Every page starts with a header record holding its generation, the page
+//! with the highest generation is the active one.
+//!
+//! The value of a record is written before its key, so a record interrupted
+//! by a reset either lacks its key or fails the `!key` check, and is skipped.
+
+use embedded_storage_async::nor_flash::NorFlash;
+
+/// Size of a flash page of the nRF52833.
+pub const PAGE_SIZE: u32 = 4096;
+/// Size of the whole storage region.
+pub const REGION_SIZE: u32 = 2 * PAGE_SIZE;
+
+const RECORD_SIZE: u32 = 8;
+/// Key of the record opening each page, its value is the page generation.
+const HEADER_KEY: u16 = 0xfffe;
+/// Key of an erased record.
+const ERASED_KEY: u16 = 0xffff;
+/// How many distinct keys survive a page swap.
+const MAX_KEYS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Page {
+    /// Address of the page.
+    start: u32,
+    /// Address of the first free record.
+    end: u32,
+    generation: u32,
+}
+
+pub struct Storage<F> {
+    flash: F,
+    /// Address of the storage region, page aligned.
+    start: u32,
+    /// `None` until the first write into a blank region.
+    active: Option<Page>,
+}
+
+impl<F: NorFlash> Storage<F> {
+    /// Opens the storage region of `REGION_SIZE` bytes starting at the page aligned `start`.
+    pub async fn new(mut flash: F, start: u32) -> Result<Self, F::Error> {
+        let mut active: Option<Page> = None;
+        for page_start in [start, start + PAGE_SIZE] {
+            if let Some((HEADER_KEY, generation)) = read_record(&mut flash, page_start).await? {
+                if active.map_or(true, |page| generation > page.generation) {
+                    active = Some(Page {
+                        start: page_start,
+                        end: page_start,
+                        generation,
+                    });
+                }
+            }
+        }
+
+        if let Some(page) = active.as_mut() {
+            page.end = page.start + RECORD_SIZE;
+            while page.end < page.start + PAGE_SIZE && !is_erased(&mut flash, page.end).await? {
+                page.end += RECORD_SIZE;
+            }
+        }
+
+        Ok(Self { flash, start, active })
+    }
+
+    /// Returns the latest value written under `key`, if any.
+    pub async fn read(&mut self, key: u16) -> Result<Option<u32>, F::Error> {
+        let Some(page) = self.active else {
+            return Ok(None);
+        };
+
+        let mut value = None;
+        let mut address = page.start + RECORD_SIZE;
+        while address < page.end {
+            if let Some((record_key, record_value)) = read_record(&mut self.flash, address).await? {
+                if record_key == key {
+                    value = Some(record_value);
+                }
+            }
+            address += RECORD_SIZE;
+        }
+        Ok(value)
+    }
+
+    /// Stores `value` under `key`, moving to the other page when the active one is full.
+    pub async fn write(&mut self, key: u16, value: u32) -> Result<(), F::Error> {
+        match self.active {
+            Some(page) if page.end < page.start + PAGE_SIZE => {
+                write_record(&mut self.flash, page.end, key, value).await?;
+                self.active = Some(Page {
+                    end: page.end + RECORD_SIZE,
+                    ..page
+                });
+                Ok(())
+            }
+            _ => self.swap_pages(key, value).await,
+        }
+    }
+
+    /// The underlying flash, for the few other users of it like the bootloader state.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Copies the latest value of every key but `key` into the other page,
+    /// followed by the new `value` of `key`.
+    async fn swap_pages(&mut self, key: u16, value: u32) -> Result<(), F::Error> {
+        let mut latest: [Option<(u16, u32)>; MAX_KEYS] = [None; MAX_KEYS];
+        if let Some(page) = self.active {
+            let mut address = page.start + RECORD_SIZE;
+            while address < page.end {
+                if let Some((record_key, record_value)) = read_record(&mut self.flash, address).await? {
+                    if record_key != key {
+                        let slot = latest
+                            .iter_mut()
+                            .find(|slot| slot.map_or(true, |(slot_key, _)| slot_key == record_key));
+                        if let Some(slot) = slot {
+                            *slot = Some((record_key, record_value));
+                        }
+                    }
+                }
+                address += RECORD_SIZE;
+            }
+        }
+
+        let (start, generation) = match self.active {
+            Some(page) if page.start == self.start => (self.start + PAGE_SIZE, page.generation + 1),
+            Some(page) => (self.start, page.generation + 1),
+            None => (self.start, 1),
+        };
+        self.flash.erase(start, start + PAGE_SIZE).await?;
+
+        // The header goes last, so a reset halfway leaves the old page active.
+        let mut end = start + RECORD_SIZE;
+        for (record_key, record_value) in latest.iter().flatten().chain([(key, value)].iter()) {
+            write_record(&mut self.flash, end, *record_key, *record_value).await?;
+            end += RECORD_SIZE;
+        }
+        write_record(&mut self.flash, start, HEADER_KEY, generation).await?;
+
+        self.active = Some(Page { start, end, generation });
+        Ok(())
+    }
+}
+
+/// Reads the record at `address`, `None` when it's erased or corrupted.
+async fn read_record<F: NorFlash>(flash: &mut F, address: u32) -> Result<Option<(u16, u32)>, F::Error> {
+    let mut buf = [0u8; RECORD_SIZE as usize];
+    flash.read(address, &mut buf).await?;
+    let key = u16::from_le_bytes([buf[0], buf[1]]);
+    let check = u16::from_le_bytes([buf[2], buf[3]]);
+    let value = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    Ok((key != ERASED_KEY && check == !key).then_some((key, value)))
+}
+
+async fn is_erased<F: NorFlash>(flash: &mut F, address: u32) -> Result<bool, F::Error> {
+    let mut buf = [0u8; RECORD_SIZE as usize];
+    flash.read(address, &mut buf).await?;
+    Ok(buf.iter().all(|byte| *byte == 0xff))
+}
+
+/// Writes a record, its value first and its key last, see the module docs.
+async fn write_record<F: NorFlash>(flash: &mut F, address: u32, key: u16, value: u32) -> Result<(), F::Error> {
+    flash.write(address + 4, &value.to_le_bytes()).await?;
+    let mut header = [0u8; 4];
+    header[0..2].copy_from_slice(&key.to_le_bytes());
+    header[2..4].copy_from_slice(&(!key).to_le_bytes());
+    flash.write(address, &header).await
+}
@@ -0,0 +1,216 @@
+//! Alerts indicated by sensors over the alert characteristic, and alerts on
+//! readings crossing the dry threshold.
+//!
+//! Unlike readings, alerts are sent as GATT indications: btleplug confirms every
+//! indication of a subscribed characteristic, which stops the sensor from
+//! sending it again, so each alert reaching this module is counted once.
+
+use crate::sink::{Reading, Sink};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Bits of the alert characteristic and the `kind` label they are counted under.
+const ALERT_KINDS: [(u8, &str); 2] = [(1 << 0, "low_battery"), (1 << 1, "probe_dropped")];
+
+/// Body posted to the webhook for every alert.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    device: &'a str,
+    kind: &'a str,
+    /// The reading that raised the alert, for alerts on readings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<f64>,
+}
+
+/// Counts confirmed alerts and forwards them to the optional webhook.
+pub struct AlertHandler {
+    alerts: IntCounterVec,
+    webhook: Option<String>,
+}
+
+impl AlertHandler {
+    pub fn new(webhook: Option<String>) -> Self {
+        AlertHandler {
+            alerts: register_int_counter_vec!(
+                "sensor_alerts_total",
+                "Alerts indicated by the sensor and confirmed by the exporter",
+                &["device", "kind"]
+            )
+            .unwrap(),
+            webhook,
+        }
+    }
+
+    /// Handles the value of an alert indication from `device`.
+    pub fn handle(&self, device: &str, value: &[u8]) {
+        let bits = value.first().copied().unwrap_or_default();
+        for (bit, kind) in ALERT_KINDS {
+            if bits & bit == 0 {
+                continue;
+            }
+            info!(device, kind, "Alert");
+            self.alerts.with_label_values(&[device, kind]).inc();
+            if let Some(url) = &self.webhook {
+                post_webhook(
+                    url.clone(),
+                    WebhookPayload {
+                        device,
+                        kind,
+                        value: None,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Consecutive readings below the dry threshold that raise a dry alert, so a
+/// single noisy sample doesn't.
+const DRY_READINGS_TO_ALERT: u32 = 3;
+/// Percentage points above the dry threshold a reading must reach before
+/// another dry alert can be raised, so readings hovering around it alert once.
+const DRY_HYSTERESIS_PERCENT: f64 = 5.0;
+
+/// Dry alert state of a device.
+#[derive(Default)]
+struct DryState {
+    /// Consecutive readings below the threshold.
+    below: u32,
+    /// Whether the alert was raised and not re-armed yet.
+    alerted: bool,
+}
+
+/// Sink posting a `dry` alert to the webhook when a device's moisture percent
+/// stays below the threshold, see `DRY_READINGS_TO_ALERT`.
+///
+/// Running as a sink keeps a slow webhook away from the BLE sessions.
+pub struct DryAlerts {
+    url: String,
+    threshold_percent: f64,
+    devices: HashMap<String, DryState>,
+    alerts: IntCounterVec,
+}
+
+impl DryAlerts {
+    pub fn new(url: String, threshold_percent: f64) -> Self {
+        DryAlerts {
+            url,
+            threshold_percent,
+            devices: HashMap::new(),
+            alerts: register_int_counter_vec!(
+                "sensor_dry_alerts_total",
+                "Dry alerts raised on the moisture percent crossing the dry threshold",
+                &["device"]
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Sink for DryAlerts {
+    fn publish(&mut self, reading: &Reading) {
+        if reading.metric != "soil_moisture_percent" {
+            return;
+        }
+        let state = self.devices.entry(reading.device.clone()).or_default();
+        if reading.value < self.threshold_percent {
+            state.below += 1;
+        } else {
+            state.below = 0;
+            if reading.value >= self.threshold_percent + DRY_HYSTERESIS_PERCENT {
+                state.alerted = false;
+            }
+        }
+        if state.below < DRY_READINGS_TO_ALERT || state.alerted {
+            return;
+        }
+
+        state.alerted = true;
+        warn!(device = %reading.device, percent = reading.value, "Too dry");
+        self.alerts.with_label_values(&[&reading.device]).inc();
+        post_webhook(
+            self.url.clone(),
+            WebhookPayload {
+                device: &reading.device,
+                kind: "dry",
+                value: Some(reading.value),
+            },
+        );
+    }
+}
+
+/// Posts an alert to the webhook in the background, failures are only logged.
+fn post_webhook(url: String, payload: WebhookPayload) {
+    let payload = serde_json::to_string(&payload).unwrap();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = ureq::post(&url)
+            .set("Content-Type", "application/json")
+            .send_string(&payload)
+        {
+            warn!(%url, %err, "Can't post alert");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_alert_bit() {
+        let handler = AlertHandler::new(None);
+        let count = |kind| handler.alerts.with_label_values(&["sensor", kind]).get();
+        handler.handle("sensor", &[0b11]);
+        assert_eq!((count("low_battery"), count("probe_dropped")), (1, 1));
+        handler.handle("sensor", &[0b01]);
+        assert_eq!((count("low_battery"), count("probe_dropped")), (2, 1));
+        // An empty or alert-free value counts nothing.
+        handler.handle("sensor", &[]);
+        handler.handle("sensor", &[0]);
+        assert_eq!((count("low_battery"), count("probe_dropped")), (2, 1));
+    }
+
+    fn percent(value: f64) -> Reading {
+        Reading {
+            device: "sensor".to_string(),
+            metric: "soil_moisture_percent",
+            value,
+            timestamp: std::time::SystemTime::now(),
+        }
+    }
+
+    // Alerts are posted in the background, to a port refusing them.
+    #[tokio::test]
+    async fn debounces_and_rearms_dry_alerts() {
+        let mut alerts = DryAlerts::new("http://127.0.0.1:1/alerts".to_string(), 20.0);
+        let count = |alerts: &DryAlerts| alerts.alerts.with_label_values(&["sensor"]).get();
+        // A single noisy sample or two don't alert, nor do other metrics.
+        for value in [10.0, 10.0, 30.0, 10.0] {
+            alerts.publish(&percent(value));
+        }
+        alerts.publish(&Reading {
+            metric: "soil_moisture",
+            ..percent(10.0)
+        });
+        assert_eq!(count(&alerts), 0);
+        alerts.publish(&percent(10.0));
+        alerts.publish(&percent(10.0));
+        assert_eq!(count(&alerts), 1);
+        alerts.publish(&percent(10.0));
+        assert_eq!(count(&alerts), 1);
+
+        // Hovering around the threshold doesn't alert again.
+        for value in [22.0, 10.0, 10.0, 10.0] {
+            alerts.publish(&percent(value));
+        }
+        assert_eq!(count(&alerts), 1);
+
+        // Rising past the hysteresis re-arms it.
+        for value in [25.0, 10.0, 10.0, 10.0] {
+            alerts.publish(&percent(value));
+        }
+        assert_eq!(count(&alerts), 2);
+    }
+}
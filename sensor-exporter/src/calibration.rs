@@ -0,0 +1,106 @@
+//! Conversion of raw soil moisture readings to a percentage, from the raw
+//! values of the probe in fully dry and fully wet soil.
+
+use tracing::warn;
+
+/// Raw readings of the probe in fully dry and fully wet soil.
+#[derive(Clone, Copy, Debug)]
+pub struct MoistureCalibration {
+    dry: u16,
+    wet: u16,
+}
+
+impl MoistureCalibration {
+    /// Builds the calibration from both points, if set. Equal points can't be
+    /// interpolated between, they are reported and left out.
+    pub fn new(dry: Option<u16>, wet: Option<u16>) -> Option<Self> {
+        let (dry, wet) = (dry?, wet?);
+        if dry == wet {
+            warn!(dry, wet, "Calibration points are equal, not exporting soil_moisture_percent");
+            return None;
+        }
+        Some(MoistureCalibration { dry, wet })
+    }
+
+    /// Whether both points are readings of an ADC whose largest reading is `full_scale`.
+    pub fn fits(&self, full_scale: u16) -> bool {
+        self.dry.max(self.wet) <= full_scale
+    }
+
+    pub fn percent(&self, raw: u16) -> f64 {
+        raw_to_percent(raw, self.dry, self.wet)
+    }
+}
+
+/// Linearly interpolates `raw` between `dry` (0%) and `wet` (100%), clamped to
+/// 0..=100. `dry` may be above `wet`, as with capacitive probes. `dry` must not
+/// equal `wet`.
+pub fn raw_to_percent(raw: u16, dry: u16, wet: u16) -> f64 {
+    let percent = (f64::from(raw) - f64::from(dry)) / (f64::from(wet) - f64::from(dry)) * 100.0;
+    percent.clamp(0.0, 100.0)
+}
+
+/// Largest raw reading of a `bits` bit ADC, `None` for resolutions a `u16` can't hold.
+pub fn full_scale(bits: u8) -> Option<u16> {
+    if !(1..=16).contains(&bits) {
+        warn!(bits, "Unsupported ADC resolution, ignoring it");
+        return None;
+    }
+    Some(((1u32 << bits) - 1) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_between_the_points() {
+        assert_eq!(raw_to_percent(1000, 1000, 3000), 0.0);
+        assert_eq!(raw_to_percent(1500, 1000, 3000), 25.0);
+        assert_eq!(raw_to_percent(2000, 1000, 3000), 50.0);
+        assert_eq!(raw_to_percent(3000, 1000, 3000), 100.0);
+    }
+
+    #[test]
+    fn clamps_to_0_100() {
+        assert_eq!(raw_to_percent(0, 1000, 3000), 0.0);
+        assert_eq!(raw_to_percent(4095, 1000, 3000), 100.0);
+    }
+
+    #[test]
+    fn interpolates_dry_above_wet() {
+        assert_eq!(raw_to_percent(3000, 3000, 1000), 0.0);
+        assert_eq!(raw_to_percent(2500, 3000, 1000), 25.0);
+        assert_eq!(raw_to_percent(1000, 3000, 1000), 100.0);
+        assert_eq!(raw_to_percent(4095, 3000, 1000), 0.0);
+        assert_eq!(raw_to_percent(0, 3000, 1000), 100.0);
+    }
+
+    #[test]
+    fn rejects_equal_points() {
+        assert!(MoistureCalibration::new(Some(2000), Some(2000)).is_none());
+    }
+
+    #[test]
+    fn needs_both_points() {
+        assert!(MoistureCalibration::new(Some(3000), None).is_none());
+        assert!(MoistureCalibration::new(None, Some(1000)).is_none());
+        let calibration = MoistureCalibration::new(Some(3000), Some(1000)).unwrap();
+        assert_eq!(calibration.percent(2000), 50.0);
+    }
+
+    #[test]
+    fn full_scale_of_resolution() {
+        assert_eq!(full_scale(12), Some(4095));
+        assert_eq!(full_scale(16), Some(u16::MAX));
+        assert_eq!(full_scale(0), None);
+        assert_eq!(full_scale(17), None);
+    }
+
+    #[test]
+    fn fits_full_scale() {
+        let calibration = MoistureCalibration::new(Some(3000), Some(1000)).unwrap();
+        assert!(calibration.fits(4095));
+        assert!(!calibration.fits(1023));
+    }
+}
@@ -0,0 +1,287 @@
+//! Optional TOML configuration file given with `--config`, for when the
+//! tunables outgrow a pile of environment variables.
+//!
+//! Keys are the long flag names, e.g. `scan-duration-secs = 5`; flags taking
+//! several values take arrays and switches take booleans. Flags and environment
+//! variables override the file, which overrides the defaults. Unknown keys and
+//! values of the wrong type are rejected with the line they are on. See
+//! `config.example.toml` for every key with its default.
+
+use crate::{notified, Cli};
+use clap::error::{ContextKind, ContextValue, ErrorKind};
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings of a configuration file, named after their long flags. Values are
+/// checked by the flags' own parsers once merged with the command line.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    adapter_index: Option<usize>,
+    bind_addr: Option<String>,
+    health_bind_addr: Option<String>,
+    dual_stack: Option<bool>,
+    name_filter: Option<String>,
+    allow_address: Option<Vec<String>>,
+    notify_uuid: Option<String>,
+    notify_metric: Option<Vec<String>>,
+    scan_service: Option<Vec<String>>,
+    scan_duration_secs: Option<u64>,
+    ble_op_timeout_secs: Option<u64>,
+    connect_attempts: Option<u32>,
+    poll_interval_secs: Option<u64>,
+    max_session_secs: Option<u64>,
+    service_time: Option<u64>,
+    max_sessions: Option<u32>,
+    moisture_endian: Option<String>,
+    device_label: Option<String>,
+    moisture_dry_raw: Option<u16>,
+    moisture_wet_raw: Option<u16>,
+    moisture_stale_secs: Option<u64>,
+    smoothing_alpha: Option<f64>,
+    plausible_range: Option<Vec<String>>,
+    drop_implausible: Option<bool>,
+    ignore_first_after_connect: Option<bool>,
+    export_timestamps: Option<bool>,
+    priority: Option<Vec<String>>,
+    poor_contact_noise: Option<f64>,
+    sink: Option<Vec<String>>,
+    influx_write_url: Option<String>,
+    pushgateway_url: Option<String>,
+    push_interval_secs: Option<u64>,
+    alert_webhook: Option<String>,
+    alert_dry_threshold: Option<f64>,
+    state_file: Option<PathBuf>,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// The settings given, by long flag name.
+    fn settings(&self) -> Result<toml::Table, Box<dyn Error>> {
+        Ok(toml::Table::try_from(self)?)
+    }
+}
+
+/// Parses the command line merged with the `--config` file, exiting with a
+/// message pointing at the offending flag or key when either is invalid.
+pub fn parse_cli() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let cli = match matches.get_one::<PathBuf>("config").cloned() {
+        None => Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()),
+        Some(path) => match parse_with_file(&matches, &path, &args, |name| std::env::var_os(name)) {
+            Ok(cli) => cli,
+            Err(err) => {
+                eprintln!("error: {}: {}", path.display(), err);
+                std::process::exit(2);
+            }
+        },
+    };
+    if let Err(err) = notified::check_distinct(&cli.export.notified_metrics) {
+        Cli::command().error(ErrorKind::ArgumentConflict, err).exit();
+    }
+    cli
+}
+
+/// Parses the command line again with the file's settings and then the
+/// environment's prepended as flags, leaving out those given on the command
+/// line. `matches` are those of the command line `cli_args` and `env` looks
+/// up environment variables.
+fn parse_with_file(
+    matches: &clap::ArgMatches,
+    path: &Path,
+    cli_args: &[OsString],
+    env: impl Fn(&str) -> Option<OsString>,
+) -> Result<Cli, Box<dyn Error>> {
+    let settings = Config::load(path)?.settings()?;
+    let command = Cli::command();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut args: Vec<OsString> = cli_args.iter().take(1).cloned().collect();
+    // Keys taken from the file, to tell which of them a parse error is about.
+    let mut taken = Vec::new();
+    for (key, value) in &settings {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| format!("key `{}` is not a flag", key))?;
+        let in_env = arg.get_env().is_some_and(|name| env(&name.to_string_lossy()).is_some());
+        if given(arg.get_id().as_str()) || in_env {
+            continue;
+        }
+        let flag = format!("--{}", key);
+        match value {
+            toml::Value::Boolean(set) => {
+                if *set {
+                    args.push(flag.into());
+                }
+            }
+            toml::Value::Array(values) => {
+                for value in values {
+                    args.push(flag.clone().into());
+                    args.push(scalar(value).into());
+                }
+            }
+            value => {
+                args.push(flag.into());
+                args.push(scalar(value).into());
+            }
+        }
+        taken.push(key.as_str());
+    }
+    // The environment is passed on as flags too, so it goes through `env`.
+    for arg in command.get_arguments() {
+        let (Some(long), Some(name)) = (arg.get_long(), arg.get_env()) else {
+            continue;
+        };
+        let Some(value) = env(&name.to_string_lossy()) else {
+            continue;
+        };
+        if given(arg.get_id().as_str()) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        if matches!(arg.get_action(), ArgAction::SetTrue) {
+            let value = value.to_string_lossy().to_ascii_lowercase();
+            if !["", "0", "n", "no", "f", "false", "off"].contains(&value.as_str()) {
+                args.push(flag.into());
+            }
+        } else {
+            args.push(flag.into());
+            args.push(value);
+        }
+    }
+    args.extend(cli_args.iter().skip(1).cloned());
+
+    let matches = command.try_get_matches_from(args).map_err(|err| {
+        let message = err.render().to_string();
+        let message = message.trim().trim_start_matches("error: ");
+        match offending_key(&err, &taken) {
+            Some(key) => format!("key `{}`: {}", key, message),
+            None => message.to_string(),
+        }
+    })?;
+    Ok(Cli::from_arg_matches(&matches)?)
+}
+
+/// Text of a value as it would be given on the command line.
+fn scalar(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// The key among `taken` whose flag a parse error is about, if any.
+fn offending_key<'a>(err: &clap::Error, taken: &[&'a str]) -> Option<&'a str> {
+    let Some(ContextValue::String(arg)) = err.get(ContextKind::InvalidArg) else {
+        return None;
+    };
+    taken.iter().copied().find(|key| {
+        let flag = format!("--{}", key);
+        arg == &flag || arg.starts_with(&format!("{} ", flag))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Parses `cli_args` after the program name with a config file holding
+    /// `toml` and the environment `env`.
+    fn parse(
+        name: &str,
+        toml: &str,
+        env: &[(&str, &str)],
+        cli_args: &[&str],
+    ) -> Result<Cli, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("sensor-exporter-{}.toml", name));
+        fs::write(&path, toml)?;
+        let env: HashMap<&str, OsString> = env.iter().map(|(k, v)| (*k, OsString::from(v))).collect();
+        let mut args: Vec<OsString> = vec!["sensor-exporter".into()];
+        args.extend(cli_args.iter().map(OsString::from));
+        let matches = Cli::command().try_get_matches_from(&args)?;
+        let result = parse_with_file(&matches, &path, &args, |name| env.get(name).cloned());
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn file_is_overridden_by_env_which_is_overridden_by_flags() {
+        let cli = parse(
+            "precedence",
+            "scan-duration-secs = 5\nble-op-timeout-secs = 30\nconnect-attempts = 5\n",
+            &[("BLE_OP_TIMEOUT_SECS", "40"), ("CONNECT_ATTEMPTS", "7")],
+            &["--connect-attempts", "9"],
+        )
+        .unwrap();
+        assert_eq!(cli.export.scan_duration_secs, 5);
+        assert_eq!(cli.export.ble_op_timeout_secs, 40);
+        assert_eq!(cli.export.connect_attempts, 9);
+    }
+
+    #[test]
+    fn takes_switches_and_arrays() {
+        let cli = parse(
+            "kinds",
+            "drop-implausible = true\nplausible-range = [\"soil_moisture=0:4000\", \"soil_temperature=-40:85\"]\n",
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert!(cli.export.drop_implausible);
+        let ranges: Vec<String> = cli.export.plausible_ranges.iter().map(ToString::to_string).collect();
+        assert_eq!(ranges, ["soil_moisture=0:4000", "soil_temperature=-40:85"]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = parse("unknown", "scan-seconds = 5\n", &[], &[]).err().unwrap();
+        assert!(err.to_string().contains("unknown field `scan-seconds`"), "{}", err);
+    }
+
+    #[test]
+    fn points_at_the_offending_key() {
+        let err = parse("wrong-type", "dual-stack = 1\n", &[], &[]).err().unwrap();
+        assert!(err.to_string().contains("dual-stack = 1"), "{}", err);
+        assert!(err.to_string().contains("invalid type: integer `1`, expected a boolean"), "{}", err);
+        let err = parse("invalid", "notify-uuid = \"soon\"\n", &[], &[]).err().unwrap();
+        assert!(err.to_string().starts_with("key `notify-uuid`: "), "{}", err);
+    }
+
+    /// Every key of the example, commented out or not, is a setting and every
+    /// setting a flag.
+    #[test]
+    fn example_covers_the_flags() {
+        let example: String = include_str!("../config.example.toml")
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.contains(" = ") => setting,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let config: Config = toml::from_str(&example).unwrap();
+        let settings = config.settings().unwrap();
+        let flags: Vec<&str> = Cli::command()
+            .get_arguments()
+            .filter_map(|arg| arg.get_long())
+            .filter(|long| !["config", "verify", "json", "once", "help", "version"].contains(long))
+            .collect();
+        for key in settings.keys() {
+            assert!(flags.contains(&key.as_str()), "{} is not a flag", key);
+        }
+        for flag in flags {
+            assert!(settings.contains_key(flag), "{} is missing from the example", flag);
+        }
+    }
+}
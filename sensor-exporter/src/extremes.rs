@@ -0,0 +1,111 @@
+//! Running minimum and maximum of every sensor's soil moisture, to spot e.g.
+//! the driest point overnight without keeping high-resolution history.
+//!
+//! The extremes run since the exporter started, or since the last
+//! `POST /reset-extremes`.
+
+use crate::sink::{Reading, Sink};
+use prometheus::{register_gauge_vec, GaugeVec};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Metric whose extremes are tracked.
+const METRIC: &str = "soil_moisture";
+
+/// Extremes of every device, shared by their sink and the HTTP server resetting them.
+pub struct Extremes {
+    min: GaugeVec,
+    max: GaugeVec,
+    /// Lowest and highest reading of every device.
+    by_device: Mutex<HashMap<String, (f64, f64)>>,
+}
+
+impl Extremes {
+    pub fn register() -> Arc<Self> {
+        Arc::new(Extremes {
+            min: register_gauge_vec!(
+                "soil_moisture_min",
+                "Lowest soil moisture reading since the exporter started or the extremes were reset",
+                &["device"]
+            )
+            .unwrap(),
+            max: register_gauge_vec!(
+                "soil_moisture_max",
+                "Highest soil moisture reading since the exporter started or the extremes were reset",
+                &["device"]
+            )
+            .unwrap(),
+            by_device: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Forgets the extremes of every device, their series return with the next reading.
+    pub fn reset(&self) {
+        self.by_device.lock().unwrap().clear();
+        self.min.reset();
+        self.max.reset();
+        info!("Reset the soil moisture extremes");
+    }
+
+    fn update(&self, device: &str, value: f64) {
+        let mut by_device = self.by_device.lock().unwrap();
+        let (min, max) = by_device
+            .entry(device.to_string())
+            .or_insert((value, value));
+        *min = min.min(value);
+        *max = max.max(value);
+        self.min.with_label_values(&[device]).set(*min);
+        self.max.with_label_values(&[device]).set(*max);
+    }
+}
+
+/// Feeds the soil moisture readings into the `Extremes`.
+pub struct ExtremesSink(pub Arc<Extremes>);
+
+impl Sink for ExtremesSink {
+    fn publish(&mut self, reading: &Reading) {
+        if reading.metric == METRIC {
+            self.0.update(&reading.device, reading.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn reading(metric: &'static str, value: f64) -> Reading {
+        Reading {
+            device: "sensor".to_string(),
+            metric,
+            value,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    // A single test, as the extremes register their gauges in the global registry.
+    #[test]
+    fn tracks_and_resets_the_extremes() {
+        let extremes = Extremes::register();
+        let mut sink = ExtremesSink(Arc::clone(&extremes));
+        let min_max = || {
+            (
+                extremes.min.with_label_values(&["sensor"]).get(),
+                extremes.max.with_label_values(&["sensor"]).get(),
+            )
+        };
+        sink.publish(&reading(METRIC, 2000.0));
+        assert_eq!(min_max(), (2000.0, 2000.0));
+        sink.publish(&reading(METRIC, 1500.0));
+        sink.publish(&reading(METRIC, 2500.0));
+        sink.publish(&reading("soil_moisture_percent", 0.0));
+        assert_eq!(min_max(), (1500.0, 2500.0));
+
+        extremes.reset();
+        assert!(extremes.by_device.lock().unwrap().is_empty());
+        sink.publish(&reading(METRIC, 1800.0));
+        assert_eq!(min_max(), (1800.0, 1800.0));
+    }
+}
@@ -0,0 +1,87 @@
+//! Metrics about the exporter itself, to tell gaps caused by gateway restarts
+//! apart from sensor-side ones.
+
+use crate::state::State;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily, MetricType};
+use prometheus::{register_gauge, register_gauge_vec, register_int_counter};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Time since the exporter started, computed when scraped.
+struct Uptime {
+    desc: Desc,
+    start: Instant,
+}
+
+impl Collector for Uptime {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut gauge = proto::Gauge::default();
+        gauge.set_value(self.start.elapsed().as_secs_f64());
+        let mut metric = proto::Metric::default();
+        metric.set_gauge(gauge);
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(vec![metric].into());
+        vec![family]
+    }
+}
+
+/// Counts this start in the state file at `state_file` and registers the exporter's own metrics.
+pub fn register(state_file: &Path) {
+    let uptime = Uptime {
+        desc: Desc::new(
+            "sensor_exporter_uptime_seconds".to_string(),
+            "Seconds since the exporter started".to_string(),
+            vec![],
+            HashMap::new(),
+        )
+        .unwrap(),
+        start: Instant::now(),
+    };
+    prometheus::register(Box::new(uptime)).unwrap();
+
+    let start_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+    register_gauge!(
+        "sensor_exporter_start_time_seconds",
+        "Unix time the exporter started"
+    )
+    .unwrap()
+    .set(start_time);
+    register_gauge_vec!(
+        "sensor_exporter_build_info",
+        "Always 1, labeled with the exporter's version",
+        &["version"]
+    )
+    .unwrap()
+    .with_label_values(&[env!("CARGO_PKG_VERSION")])
+    .set(1.0);
+
+    // A broken state file must not keep the exporter from exporting.
+    let mut state = State::load(state_file).unwrap_or_else(|err| {
+        warn!(state_file = %state_file.display(), %err, "Can't load state");
+        State::default()
+    });
+    state.restarts += 1;
+    if let Err(err) = state.save(state_file) {
+        warn!(state_file = %state_file.display(), %err, "Can't save state");
+    }
+
+    register_int_counter!(
+        "sensor_exporter_restarts_total",
+        "Number of times the exporter was started, kept in the state file"
+    )
+    .unwrap()
+    .inc_by(state.restarts);
+}
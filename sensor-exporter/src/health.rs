@@ -0,0 +1,87 @@
+//! Liveness of the exporter for the `/healthz` endpoint, cheap to check and
+//! apart from the scrape semantics of `/metrics`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time without a scan cycle after which the main loop counts as stuck, well
+/// above the longest scan backoff.
+const LOOP_STALL_AFTER: Duration = Duration::from_secs(300);
+
+/// When the main loop last went around and when a sensor was last seen.
+pub struct Health {
+    /// Time without a reading after which no sensor counts as seen, `None` never.
+    stale_after: Option<Duration>,
+    loop_alive_at: Mutex<Instant>,
+    /// Last reading of any sensor, the start time before the first one.
+    reading_at: Mutex<Instant>,
+}
+
+impl Health {
+    pub fn new(stale_after: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Health {
+            stale_after,
+            loop_alive_at: Mutex::new(now),
+            reading_at: Mutex::new(now),
+        }
+    }
+
+    /// Records that the main loop went around.
+    pub fn loop_alive(&self) {
+        *self.loop_alive_at.lock().unwrap() = Instant::now();
+    }
+
+    /// Records a reading of any sensor.
+    pub fn reading_seen(&self) {
+        *self.reading_at.lock().unwrap() = Instant::now();
+    }
+
+    /// Why the exporter is unhealthy, `None` when it's healthy.
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.loop_alive_at.lock().unwrap().elapsed() >= LOOP_STALL_AFTER {
+            return Some("main loop stalled");
+        }
+        match self.stale_after {
+            Some(stale_after) if self.reading_at.lock().unwrap().elapsed() >= stale_after => {
+                Some("no sensor seen recently")
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn unhealthy_without_a_recent_reading() {
+        let health = Health::new(Some(Duration::from_millis(50)));
+        assert_eq!(health.problem(), None);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(health.problem(), Some("no sensor seen recently"));
+        health.reading_seen();
+        assert_eq!(health.problem(), None);
+    }
+
+    #[test]
+    fn never_stale_without_a_window() {
+        let health = Health::new(None);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(health.problem(), None);
+    }
+
+    #[test]
+    fn unhealthy_while_the_main_loop_stalls() {
+        let health = Health::new(None);
+        let Some(stalled) = Instant::now().checked_sub(LOOP_STALL_AFTER) else {
+            return;
+        };
+        *health.loop_alive_at.lock().unwrap() = stalled;
+        assert_eq!(health.problem(), Some("main loop stalled"));
+        health.loop_alive();
+        assert_eq!(health.problem(), None);
+    }
+}
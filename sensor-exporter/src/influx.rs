@@ -0,0 +1,170 @@
+//! Sink writing readings to InfluxDB (or Telegraf) in line protocol.
+//!
+//! Readings of one notification become a single `soil_moisture` point with a
+//! field per metric. Points are batched until the next flush, and kept for the
+//! next one when writing them fails.
+
+use crate::sink::{Reading, Sink};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Most points kept while the endpoint is unreachable, the oldest are dropped first.
+const MAX_PENDING_POINTS: usize = 10_000;
+/// How long a write may take before it's failed, so a hung endpoint can't stall the sink.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Readings of one device received at the same time.
+struct Point {
+    device: String,
+    timestamp: SystemTime,
+    fields: Vec<(&'static str, f64)>,
+}
+
+impl Point {
+    fn to_line(&self) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect();
+        let timestamp_ns = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos());
+        format!(
+            "soil_moisture,device={} {} {}",
+            escape_tag(&self.device),
+            fields.join(","),
+            timestamp_ns
+        )
+    }
+}
+
+/// Escapes the characters line protocol gives a meaning to in tag values.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// POSTs batches of points to an InfluxDB write endpoint, e.g.
+/// `http://localhost:8086/write?db=plants` (nanosecond timestamps).
+pub struct InfluxSink {
+    url: String,
+    agent: ureq::Agent,
+    points: Vec<Point>,
+}
+
+impl InfluxSink {
+    pub fn new(url: String) -> Self {
+        InfluxSink {
+            url,
+            agent: ureq::AgentBuilder::new().timeout(WRITE_TIMEOUT).build(),
+            points: Vec::new(),
+        }
+    }
+}
+
+/// Field of the point holding a metric.
+fn field(metric: &'static str) -> &'static str {
+    match metric {
+        "soil_moisture" => "raw",
+        "soil_moisture_smoothed" => "smoothed",
+        "soil_moisture_percent" => "percent",
+        _ => metric,
+    }
+}
+
+impl Sink for InfluxSink {
+    fn publish(&mut self, reading: &Reading) {
+        let field = (field(reading.metric), reading.value);
+        match self.points.last_mut() {
+            Some(point)
+                if point.device == reading.device && point.timestamp == reading.timestamp =>
+            {
+                point.fields.push(field)
+            }
+            _ => self.points.push(Point {
+                device: reading.device.clone(),
+                timestamp: reading.timestamp,
+                fields: vec![field],
+            }),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.points.is_empty() {
+            return;
+        }
+        let body: Vec<String> = self.points.iter().map(Point::to_line).collect();
+        let result = tokio::task::block_in_place(|| {
+            self.agent.post(&self.url).send_string(&body.join("\n"))
+        });
+        match result {
+            Ok(_) => self.points.clear(),
+            Err(err) => {
+                warn!(
+                    points = self.points.len(),
+                    url = %self.url,
+                    %err,
+                    "Can't write points, retrying on the next flush"
+                );
+                let excess = self.points.len().saturating_sub(MAX_PENDING_POINTS);
+                self.points.drain(..excess);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn reading(device: &str, metric: &'static str, value: f64, timestamp: SystemTime) -> Reading {
+        Reading {
+            device: device.to_string(),
+            metric,
+            value,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn formats_line_protocol() {
+        let point = Point {
+            device: "living room, east=1".to_string(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1),
+            fields: vec![("raw", 1234.0), ("percent", 42.5)],
+        };
+        assert_eq!(
+            point.to_line(),
+            "soil_moisture,device=living\\ room\\,\\ east\\=1 raw=1234,percent=42.5 1000000000"
+        );
+    }
+
+    #[test]
+    fn groups_readings_of_one_notification() {
+        let mut sink = InfluxSink::new("http://localhost:8086/write".to_string());
+        let first = UNIX_EPOCH + Duration::from_secs(1);
+        let second = UNIX_EPOCH + Duration::from_secs(2);
+        sink.publish(&reading("a", "soil_moisture", 1000.0, first));
+        sink.publish(&reading("a", "soil_moisture_percent", 50.0, first));
+        sink.publish(&reading("b", "soil_moisture", 2000.0, first));
+        sink.publish(&reading("a", "soil_moisture", 1010.0, second));
+        let lines: Vec<String> = sink.points.iter().map(Point::to_line).collect();
+        assert_eq!(
+            lines,
+            [
+                "soil_moisture,device=a raw=1000,percent=50 1000000000",
+                "soil_moisture,device=b raw=2000 1000000000",
+                "soil_moisture,device=a raw=1010 2000000000",
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! Decoding of the values notified by the sensors, kept apart from the
+//! binary so it can be tested without a sensor.
+
+use clap::ValueEnum;
+
+/// Byte order of the notified readings.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endian {
+    /// Low byte first, as the micro:bit firmware notifies them.
+    #[default]
+    Little,
+    Big,
+}
+
+/// Decodes a soil moisture reading, a `u16` in `endian` byte order possibly
+/// followed by more bytes. Returns `None` when the payload is too short.
+pub fn decode_moisture(bytes: &[u8], endian: Endian) -> Option<u16> {
+    match (bytes, endian) {
+        ([first, second, ..], Endian::Little) => Some(u16::from_le_bytes([*first, *second])),
+        ([first, second, ..], Endian::Big) => Some(u16::from_be_bytes([*first, *second])),
+        _ => None,
+    }
+}
+
+/// Decodes a signed reading, e.g. a temperature, an `i16` in `endian` byte order
+/// possibly followed by more bytes. Returns `None` when the payload is too short.
+pub fn decode_signed(bytes: &[u8], endian: Endian) -> Option<i16> {
+    decode_moisture(bytes, endian).map(|value| value as i16)
+}
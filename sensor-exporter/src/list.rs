@@ -0,0 +1,52 @@
+//! Listing of every peripheral the adapters see, to diagnose name filter mismatches.
+
+use btleplug::api::{Central, Peripheral as _, ScanFilter};
+use btleplug::platform::Adapter;
+use clap::Args;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time;
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Seconds to scan for before listing the peripherals.
+    #[arg(long, default_value_t = 5)]
+    duration_secs: u64,
+}
+
+/// Scans every adapter and prints the peripherals found.
+pub async fn run(adapter_list: &[Adapter], args: ListArgs) -> Result<(), Box<dyn Error>> {
+    for adapter in adapter_list.iter() {
+        let info = adapter
+            .adapter_info()
+            .await
+            .unwrap_or_else(|_| String::from("(adapter info unknown)"));
+        println!("Adapter {}:", info);
+        adapter.start_scan(ScanFilter::default()).await?;
+        time::sleep(Duration::from_secs(args.duration_secs)).await;
+        adapter.stop_scan().await?;
+
+        for peripheral in adapter.peripherals().await? {
+            let Some(properties) = peripheral.properties().await? else {
+                println!("  {} (no properties)", peripheral.address());
+                continue;
+            };
+            let rssi = properties
+                .rssi
+                .map_or(String::from("unknown"), |rssi| format!("{} dBm", rssi));
+            let services: Vec<String> = properties
+                .services
+                .iter()
+                .map(|service| service.to_string())
+                .collect();
+            println!(
+                "  {} {:?}, RSSI: {}, services: [{}]",
+                peripheral.address(),
+                properties.local_name.unwrap_or_default(),
+                rssi,
+                services.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
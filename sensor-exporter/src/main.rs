@@ -2,134 +2,1413 @@
 // Big Sur or later.
 
 use btleplug::api::{
-    bleuuid::uuid_from_u16, Central, CharPropFlags, Manager as _, Peripheral, ScanFilter,
+    bleuuid::uuid_from_u16, BDAddr, Central, CentralEvent, CharPropFlags, Characteristic,
+    Manager as _, Peripheral, ScanFilter, ValueNotification,
 };
-use btleplug::platform::{Adapter, Manager};
-use futures::StreamExt;
-use prometheus_exporter::{
-    self,
-    prometheus::core::{AtomicF64, GenericGauge},
-    prometheus::register_gauge,
+use alerts::{AlertHandler, DryAlerts};
+use backoff::Backoff;
+use calibration::MoistureCalibration;
+use extremes::{Extremes, ExtremesSink};
+use health::Health;
+use notified::NotifiedMetric;
+use btleplug::platform::{Adapter, Manager, Peripheral as PlatformPeripheral, PeripheralId};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::{future, Stream, StreamExt};
+use plausibility::{PlausibilityCheck, PlausibleRange};
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    GaugeVec, HistogramVec, IntCounter, IntCounterVec,
 };
+use schedule::{DevicePriority, Scheduler};
+use sensor_exporter::{decode_moisture, decode_signed, Endian};
+use sink::{Reading, SinkKind};
+use smoothing::Ewma;
+use std::collections::HashMap;
 use std::error::Error;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
 use tokio::time;
 use tokio::time::timeout;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-/// Only devices whose name contains this string will be tried.
+mod alerts;
+mod backoff;
+mod calibration;
+mod config;
+mod extremes;
+mod gateway;
+mod health;
+mod influx;
+mod list;
+mod manage;
+mod mock;
+mod notified;
+mod once;
+mod plausibility;
+mod push;
+mod schedule;
+mod server;
+mod sink;
+mod smoothing;
+mod snapshot;
+mod state;
+mod timestamped;
+mod update;
+mod verify;
+
+/// Default of `--name-filter`: only devices whose name contains it will be tried.
 const PERIPHERAL_NAME_MATCH_FILTER: &str = "MicroBit";
-/// UUID of the characteristic for which we should subscribe to notifications.
-const NOTIFY_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbabe);
+/// Default of `--notify-uuid`, the characteristic notifying soil moisture readings.
+const NOTIFY_CHARACTERISTIC_UUID: &str = "babe";
+/// UUID of the sensor status characteristic, a byte of `FLAG_*` bits.
+const STATUS_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab1);
+/// Set in the status byte while the sensor captures calibration points.
+const FLAG_CALIBRATING: u8 = 1 << 0;
+/// Set in the status byte when the sensor's battery is nearly empty.
+const FLAG_LOW_BATTERY: u8 = 1 << 1;
+/// Set in the status byte when the last reading left out a disconnected probe.
+const FLAG_PROBE_DROPPED: u8 = 1 << 3;
+/// UUID of the characteristic notifying the standard deviation of the samples behind each reading.
+const NOISE_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab4);
+/// UUID of the alert characteristic, sent as acknowledged indications, see `alerts`.
+const ALERT_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab3);
+/// UUID of the diagnostics characteristic holding the sensor's boot count.
+const BOOT_COUNT_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xd1a0);
+/// UUID of the diagnostics characteristic holding the sensor's unused stack bytes.
+const FREE_RAM_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xd1a1);
+/// UUID of the standard Battery Level characteristic (Battery Service `0x180f`), a percentage.
+const BATTERY_LEVEL_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a19);
+/// UUID of the standard Firmware Revision String characteristic (Device Information Service `0x180a`).
+const FIRMWARE_REVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a26);
+/// UUID of the characteristic holding the resolution of the sensor's raw readings in bits.
+const ADC_RESOLUTION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbabd);
+/// Company identifier under which sensors advertise their status byte.
+const MANUFACTURER_ID: u16 = 0xffff;
 
-#[tokio::main]
-async fn main() {
-    pretty_env_logger::init();
-    let binding = "127.0.0.1:3737".parse().unwrap();
-    prometheus_exporter::start(binding).unwrap();
-    let gauge = register_gauge!("soil_moisture", "help").unwrap();
+/// Metrics exposed on the `/metrics` endpoint, besides the readings published by `PrometheusSink`.
+struct Metrics {
+    plausibility: PlausibilityCheck,
+    alerts: AlertHandler,
+    malformed_packets: IntCounterVec,
+    reconnects: IntCounterVec,
+    scan_failures: IntCounter,
+    connected: GaugeVec,
+    rssi: GaugeVec,
+    calibrating: GaugeVec,
+    low_battery: GaugeVec,
+    probe_dropped: GaugeVec,
+    noise: GaugeVec,
+    poor_contact: GaugeVec,
+    boot_count: GaugeVec,
+    free_ram: GaugeVec,
+    battery: GaugeVec,
+    adc_full_scale: GaugeVec,
+    firmware: GaugeVec,
+    last_update: GaugeVec,
+    first_reading: HistogramVec,
+}
+
+/// Everything the sessions with the sensors share.
+struct Context {
+    args: ExportArgs,
+    calibration: Option<MoistureCalibration>,
+    metrics: Metrics,
+    readings: mpsc::Sender<Reading>,
+    health: Arc<Health>,
+    /// Adapter and identifier of every sensor a session ended with cleanly, to
+    /// reconnect to without a scan.
+    known_peripherals: Mutex<HashMap<String, (Adapter, PeripheralId)>>,
+    scheduler: Mutex<Scheduler>,
+    /// Notified whenever a session ends, so its sensor is picked up again right away.
+    session_ended: Notify,
+}
+
+/// Exports soil moisture readings of MicroBit sensors in Prometheus format.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML file of settings keyed by their long flag names, e.g.
+    /// `scan-duration-secs = 5`. Flags and environment variables override it.
+    #[arg(long, env = "EXPORTER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Run a single end-to-end check of the setup, print a diagnostic summary and exit.
+    #[arg(long)]
+    verify: bool,
+    /// Print the `--verify` summary as JSON.
+    #[arg(long, requires = "verify")]
+    json: bool,
+    /// Only use the Bluetooth adapter at this index instead of all of them.
+    #[arg(long, env = "BLE_ADAPTER_INDEX", global = true)]
+    adapter_index: Option<usize>,
+    /// Print the current reading of the first matching sensor and exit.
+    #[arg(long, conflicts_with = "verify")]
+    once: bool,
+    #[command(flatten)]
+    export: ExportArgs,
+}
 
-    let manager = Manager::new().await.unwrap();
-    let adapter_list: Vec<btleplug::platform::Adapter> = manager.adapters().await.unwrap();
+/// Options of the exporter itself, ignored by subcommands.
+#[derive(Args)]
+struct ExportArgs {
+    /// Address to serve metrics on, takes precedence over `--bind-addr`.
+    #[arg(value_name = "BIND_ADDR", value_parser = server::parse_addr)]
+    bind_addr_positional: Option<SocketAddr>,
+    /// Address to serve metrics on, IPv6 addresses go in brackets, e.g. `[::]:3737`.
+    #[arg(
+        long,
+        env = "EXPORTER_BIND_ADDR",
+        default_value = "127.0.0.1:3737",
+        value_parser = server::parse_addr
+    )]
+    bind_addr: SocketAddr,
+    /// Address to also serve `/healthz` on, e.g. for a liveness probe on its own port.
+    #[arg(long, env = "HEALTH_BIND_ADDR", value_parser = server::parse_addr)]
+    health_bind_addr: Option<SocketAddr>,
+    /// Accept IPv4 connections on an IPv6 `--bind-addr` too.
+    #[arg(long)]
+    dual_stack: bool,
+    /// Only peripherals whose name contains this string are tried, an empty
+    /// filter tries every peripheral.
+    #[arg(long, env = "PERIPHERAL_NAME_FILTER", default_value = PERIPHERAL_NAME_MATCH_FILTER)]
+    name_filter: String,
+    /// Only connect to peripherals with these addresses, whatever their name,
+    /// instead of going by `--name-filter`. Comma separated in the environment.
+    #[arg(long = "allow-address", env = "PERIPHERAL_ADDRESS_ALLOWLIST", value_delimiter = ',')]
+    address_allowlist: Vec<BDAddr>,
+    /// UUID of the characteristic notifying readings, either 16-bit like `babe`
+    /// or a full 128-bit UUID.
+    #[arg(
+        long,
+        env = "NOTIFY_CHAR_UUID",
+        default_value = NOTIFY_CHARACTERISTIC_UUID,
+        value_parser = parse_uuid
+    )]
+    notify_uuid: Uuid,
+    /// Another characteristic notifying readings of its own as `uuid=metric`,
+    /// e.g. `babc=soil_temperature`, repeat for several. Values are decoded as
+    /// signed 16-bit integers and exported as the `metric` gauge.
+    #[arg(long = "notify-metric", env = "NOTIFY_METRICS", value_delimiter = ',')]
+    notified_metrics: Vec<NotifiedMetric>,
+    /// Only surface peripherals advertising one of these services while scanning,
+    /// e.g. `181a` as the sensor firmware does. Scans are unfiltered by default.
+    #[arg(
+        long = "scan-service",
+        env = "SCAN_SERVICE_UUIDS",
+        value_delimiter = ',',
+        value_parser = parse_uuid
+    )]
+    scan_services: Vec<Uuid>,
+    /// Seconds to scan for peripherals before going through them.
+    #[arg(long, env = "SCAN_DURATION_SECS", default_value_t = 2)]
+    scan_duration_secs: u64,
+    /// Seconds to wait for a BLE operation, like connecting or reading a
+    /// characteristic, and for the next notification before disconnecting.
+    #[arg(long, env = "BLE_OP_TIMEOUT_SECS", default_value_t = 25)]
+    ble_op_timeout_secs: u64,
+    /// Attempts to connect to a sensor before giving up on it until the next scan.
+    #[arg(
+        long,
+        env = "CONNECT_ATTEMPTS",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    connect_attempts: u32,
+    /// Also read the reading characteristic every this many seconds, for
+    /// sensors whose notifications stall or that don't notify at all.
+    #[arg(long, env = "POLL_INTERVAL_SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    poll_interval_secs: Option<u64>,
+    /// Seconds after which to disconnect from a sensor and reconnect right away,
+    /// for BLE stacks whose long-lived subscriptions stop notifying without a
+    /// disconnect. By default stay connected.
+    #[arg(long, env = "MAX_SESSION_SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    max_session_secs: Option<u64>,
+    /// Byte order of the notified readings.
+    #[arg(long, env = "MOISTURE_ENDIAN", value_enum, default_value_t = Endian::Little)]
+    moisture_endian: Endian,
+    /// What identifies a sensor in the `device` label of its metrics. Sensors
+    /// not advertising a name are identified by their address either way.
+    #[arg(long, value_enum, default_value_t = DeviceLabel::Name)]
+    device_label: DeviceLabel,
+    /// Where to publish readings, repeat to publish to several sinks at once.
+    #[arg(long = "sink", value_enum, default_values_t = [SinkKind::Prometheus])]
+    sinks: Vec<SinkKind>,
+    /// InfluxDB write endpoint of the `influx` sink, e.g.
+    /// `http://localhost:8086/write?db=plants`. Setting it enables the sink.
+    #[arg(long, env = "INFLUX_WRITE_URL", required_if_eq("sinks", "influx"))]
+    influx_write_url: Option<String>,
+    /// Pushgateway to push all metrics to, e.g. `http://pushgateway:9091`, for
+    /// when Prometheus can't scrape the exporter. `/metrics` is served still.
+    #[arg(long, env = "PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+    /// Seconds between pushes to `--pushgateway-url`.
+    #[arg(long, env = "PUSH_INTERVAL_SECS", default_value_t = 15)]
+    push_interval_secs: u64,
+    /// Plausible range of a metric as `metric=min:max`, repeat for several metrics.
+    /// Readings outside of it are counted in `sensor_implausible_readings_total`.
+    /// Comma separated in the environment.
+    #[arg(
+        long = "plausible-range",
+        env = "PLAUSIBLE_RANGES",
+        value_delimiter = ',',
+        default_value = "soil_moisture=0:4095"
+    )]
+    plausible_ranges: Vec<PlausibleRange>,
+    /// Don't publish implausible readings at all.
+    #[arg(long, env = "DROP_IMPLAUSIBLE")]
+    drop_implausible: bool,
+    /// Skip the first reading after every (re)connect, which may be the stale
+    /// value the sensor kept while no client was listening.
+    #[arg(long)]
+    ignore_first_after_connect: bool,
+    /// EWMA smoothing factor within [0, 1] exporting the smoothed soil moisture
+    /// as `soil_moisture_smoothed`, `soil_moisture` stays raw. Unset or 0 disables it.
+    #[arg(long, env = "MOISTURE_EMA_ALPHA", value_parser = smoothing::parse_alpha)]
+    smoothing_alpha: Option<f64>,
+    /// Raw reading of the probe in fully dry soil, with `--moisture-wet-raw`
+    /// exports `soil_moisture_percent`.
+    #[arg(long, env = "MOISTURE_DRY_RAW", requires = "moisture_wet_raw")]
+    moisture_dry_raw: Option<u16>,
+    /// Raw reading of the probe in fully wet soil.
+    #[arg(long, env = "MOISTURE_WET_RAW", requires = "moisture_dry_raw")]
+    moisture_wet_raw: Option<u16>,
+    /// Seconds without a reading after which a sensor's reading gauges read NaN
+    /// until its next reading, 0 keeps the last value forever.
+    #[arg(long, env = "MOISTURE_STALE_SECS", default_value_t = 60)]
+    moisture_stale_secs: u64,
+    /// Expose readings with the time they were received instead of the scrape time.
+    #[arg(long)]
+    export_timestamps: bool,
+    /// Priority of a sensor as `device=priority` (1 by default), repeat for
+    /// several sensors. `device` is its `device` label, so its address with
+    /// `--device-label address`. Sensors are serviced most overdue first,
+    /// weighted by their priority. Comma separated in the environment.
+    #[arg(long = "priority", env = "DEVICE_PRIORITIES", value_delimiter = ',')]
+    priorities: Vec<DevicePriority>,
+    /// Seconds to stay connected to a sensor before moving on to the next one,
+    /// so a single adapter can take turns among many sensors. By default stay
+    /// connected until the sensor goes quiet.
+    #[arg(long, env = "SERVICE_TIME_SECS")]
+    service_time: Option<u64>,
+    /// Most sensors to stay connected to at once across all adapters, free
+    /// slots going to the most overdue sensors. 7 by default, the connection
+    /// limit of many adapters.
+    #[arg(
+        long,
+        env = "MAX_SESSIONS",
+        default_value_t = 7,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_sessions: u32,
+    /// URL to POST a JSON `{"device", "kind"}` object to for every alert a sensor
+    /// indicates, and for dry alerts with the reading's `value`.
+    #[arg(long, env = "ALERT_WEBHOOK_URL")]
+    alert_webhook: Option<String>,
+    /// Moisture percent below which a sensor's readings raise a dry alert, see
+    /// `--alert-webhook`. Needs the calibration points.
+    #[arg(
+        long,
+        env = "ALERT_DRY_THRESHOLD",
+        requires_all = ["alert_webhook", "moisture_dry_raw"]
+    )]
+    alert_dry_threshold: Option<f64>,
+    /// Signal noise (standard deviation in raw ADC units) above which
+    /// `sensor_poor_contact` is set, unset by default.
+    #[arg(long, env = "POOR_CONTACT_NOISE")]
+    poor_contact_noise: Option<f64>,
+    /// File keeping the exporter's state across restarts, by default
+    /// `sensor-exporter/state.json` in `$XDG_STATE_HOME` or `~/.local/state`.
+    #[arg(long, env = "STATE_FILE", default_value_os_t = state::default_path())]
+    state_file: PathBuf,
+}
 
-    if adapter_list.is_empty() {
-        eprintln!("No Bluetooth adapters found");
+impl ExportArgs {
+    fn scan_duration(&self) -> Duration {
+        Duration::from_secs(self.scan_duration_secs)
     }
 
-    loop {
-        let _ = scan_and_subscribe(&adapter_list, &gauge).await; // poor-man's supervision
+    fn ble_timeout(&self) -> Duration {
+        Duration::from_secs(self.ble_op_timeout_secs)
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_secs.map(Duration::from_secs)
+    }
+
+    fn max_session(&self) -> Option<Duration> {
+        self.max_session_secs.map(Duration::from_secs)
+    }
+
+    /// A fresh moving average of the readings, unless smoothing is disabled.
+    fn ewma(&self) -> Option<Ewma> {
+        self.smoothing_alpha.filter(|alpha| *alpha > 0.0).map(Ewma::new)
+    }
+
+    fn max_sessions(&self) -> usize {
+        self.max_sessions as usize
+    }
+
+    /// Metric of the readings notified by the characteristic `uuid`, if it's a `--notify-metric` one.
+    fn notified_metric(&self, uuid: Uuid) -> Option<&'static str> {
+        self.notified_metrics
+            .iter()
+            .find(|notified| notified.uuid == uuid)
+            .map(|notified| notified.metric)
+    }
+
+    /// Whether a peripheral is one of our sensors, by address when there's an
+    /// allowlist and by name otherwise.
+    fn matches(&self, address: BDAddr, local_name: &str) -> bool {
+        if self.address_allowlist.is_empty() {
+            local_name.contains(self.name_filter.as_str())
+        } else {
+            self.address_allowlist.contains(&address)
+        }
     }
 }
 
-async fn scan_and_subscribe(
-    adapter_list: &Vec<Adapter>,
-    gauge: &GenericGauge<AtomicF64>,
-) -> Result<(), Box<dyn Error>> {
+/// Identifier of a sensor used as its `device` label.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceLabel {
+    /// The advertised local name, sensors must be given distinct names.
+    Name,
+    /// The Bluetooth address, stable without renaming sensors.
+    Address,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send a management command to a sensor and exit.
+    Manage(manage::ManageArgs),
+    /// List every peripheral in range with its address, RSSI and services, then exit.
+    List(list::ListArgs),
+}
+
+#[tokio::main]
+async fn main() {
+    // Logs go to stderr, stdout is reserved for the output of `--once` and the subcommands.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+    let cli = config::parse_cli();
+
+    // Mock sensors need no Bluetooth stack at all, e.g. in CI.
+    let adapter_list = if cfg!(feature = "mock-ble") {
+        Vec::new()
+    } else {
+        match find_adapters(cli.adapter_index).await {
+            Ok(adapter_list) if adapter_list.is_empty() => {
+                error!("No Bluetooth adapters found");
+                adapter_list
+            }
+            Ok(adapter_list) => adapter_list,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    };
     for adapter in adapter_list.iter() {
-        println!("Starting scan...");
-        adapter
-            .start_scan(ScanFilter::default())
+        let info = adapter
+            .adapter_info()
             .await
-            .expect("Can't scan BLE adapter for connected devices...");
+            .unwrap_or_else(|_| String::from("(adapter info unknown)"));
+        info!(adapter = %info, "Using Bluetooth adapter");
+    }
+
+    if cli.verify {
+        let success = verify::run(&adapter_list, &cli.export, cli.json).await;
+        std::process::exit(if success { 0 } else { 1 });
+    }
+
+    if cli.once {
+        match once::run(&adapter_list, &cli.export).await {
+            Ok(reading) => println!("{}", reading),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    match cli.command {
+        Some(Command::Manage(args)) => {
+            if let Err(err) = manage::run(&adapter_list, &cli.export, args).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::List(args)) => {
+            if let Err(err) = list::run(&adapter_list, args).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        None => export(adapter_list, cli.adapter_index, cli.export).await,
+    }
+}
+
+/// Looks the Bluetooth adapters up, only the one at `index` when given.
+async fn find_adapters(index: Option<usize>) -> Result<Vec<Adapter>, Box<dyn Error>> {
+    let mut adapter_list = Manager::new().await?.adapters().await?;
+    if let Some(index) = index {
+        if index >= adapter_list.len() {
+            return Err(format!(
+                "Bluetooth adapter index {} is out of range, {} adapters found",
+                index,
+                adapter_list.len()
+            )
+            .into());
+        }
+        adapter_list = vec![adapter_list.swap_remove(index)];
+    }
+    Ok(adapter_list)
+}
 
-        time::sleep(Duration::from_secs(2)).await;
+/// Buckets of `sensor_first_reading_seconds`, from a quick scan and connect up
+/// to a sensor notifying only every minute or so.
+const FIRST_READING_BUCKETS: [f64; 11] = [1.0, 2.0, 3.0, 5.0, 7.5, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0];
 
-        let peripherals = adapter.peripherals().await?;
+/// Consecutive failed scan cycles after which the adapters are looked up again,
+/// picking up e.g. a replugged USB dongle.
+const ADAPTER_REFRESH_FAILURES: u32 = 3;
+
+/// Serves the metrics endpoint and keeps subscribing to sensors forever.
+async fn export(
+    mut adapter_list: Vec<Adapter>,
+    adapter_index: Option<usize>,
+    args: ExportArgs,
+) {
+    let stale_after =
+        (args.moisture_stale_secs > 0).then(|| Duration::from_secs(args.moisture_stale_secs));
+    let health = Arc::new(Health::new(stale_after));
+    let extremes = Extremes::register();
+    let bind_addr = args.bind_addr_positional.unwrap_or(args.bind_addr);
+    for bind_addr in std::iter::once(bind_addr).chain(args.health_bind_addr) {
+        let listener = match server::bind(bind_addr, args.dual_stack) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!(%bind_addr, %err, "Can't listen");
+                std::process::exit(1);
+            }
+        };
+        server::start(listener, Arc::clone(&health), Arc::clone(&extremes)).unwrap();
+    }
+    gateway::register(&args.state_file);
+
+    let (readings, readings_rx) = mpsc::channel(64);
+    // Every sink once, however often it's given.
+    let mut sink_kinds = Vec::new();
+    let influx = args.influx_write_url.is_some().then_some(SinkKind::Influx);
+    for kind in args.sinks.iter().copied().chain(influx) {
+        if !sink_kinds.contains(&kind) {
+            sink_kinds.push(kind);
+        }
+    }
+    let mut sinks: Vec<_> = sink_kinds.iter().map(|kind| kind.build(&args)).collect();
+    if let (Some(url), Some(threshold)) = (&args.alert_webhook, args.alert_dry_threshold) {
+        sinks.push(Box::new(DryAlerts::new(url.clone(), threshold)));
+    }
+    sinks.push(Box::new(ExtremesSink(extremes)));
+    tokio::spawn(sink::run(sinks, readings_rx, Duration::from_secs(10)));
+    if let Some(url) = args.pushgateway_url.clone() {
+        info!(%url, "Pushing metrics");
+        tokio::spawn(push::run(url, Duration::from_secs(args.push_interval_secs)));
+    }
+
+    let metrics = Metrics {
+        plausibility: PlausibilityCheck::new(args.plausible_ranges.clone(), args.drop_implausible),
+        alerts: AlertHandler::new(args.alert_webhook.clone()),
+        malformed_packets: register_int_counter_vec!(
+            "sensor_malformed_packets_total",
+            "Reading notifications too short to decode, e.g. truncated on a flaky connection",
+            &["device"]
+        )
+        .unwrap(),
+        reconnects: register_int_counter_vec!(
+            "sensor_reconnects_total",
+            "Sessions with the sensor that ended, whether it went quiet, disconnected or failed",
+            &["device"]
+        )
+        .unwrap(),
+        scan_failures: register_int_counter!(
+            "sensor_scan_failures_total",
+            "Scans the Bluetooth adapter failed to start"
+        )
+        .unwrap(),
+        connected: register_gauge_vec!(
+            "sensor_connected",
+            "1 while subscribed to the sensor's readings, 0 once disconnected",
+            &["device"]
+        )
+        .unwrap(),
+        rssi: register_gauge_vec!(
+            "sensor_rssi_dbm",
+            "Signal strength of the sensor as last seen by the adapter",
+            &["device"]
+        )
+        .unwrap(),
+        calibrating: register_gauge_vec!(
+            "sensor_calibrating",
+            "1 while the sensor captures calibration points, readings are unreliable meanwhile",
+            &["device"]
+        )
+        .unwrap(),
+        low_battery: register_gauge_vec!(
+            "sensor_low_battery",
+            "1 when the sensor reports a nearly empty battery",
+            &["device"]
+        )
+        .unwrap(),
+        probe_dropped: register_gauge_vec!(
+            "sensor_probe_dropped",
+            "1 when the sensor left a disconnected probe out of its last reading",
+            &["device"]
+        )
+        .unwrap(),
+        noise: register_gauge_vec!(
+            "sensor_signal_noise",
+            "Standard deviation of the raw samples behind the last reading, high on a loose contact",
+            &["device"]
+        )
+        .unwrap(),
+        poor_contact: register_gauge_vec!(
+            "sensor_poor_contact",
+            "1 when the signal noise is above --poor-contact-noise",
+            &["device"]
+        )
+        .unwrap(),
+        boot_count: register_gauge_vec!(
+            "sensor_boot_count",
+            "Number of times the sensor booted",
+            &["device"]
+        )
+        .unwrap(),
+        free_ram: register_gauge_vec!(
+            "sensor_free_ram_bytes",
+            "Stack bytes the sensor never used since boot",
+            &["device"]
+        )
+        .unwrap(),
+        battery: register_gauge_vec!(
+            "sensor_battery_percent",
+            "Battery level from the standard Battery Service, for sensors exposing it",
+            &["device"]
+        )
+        .unwrap(),
+        adc_full_scale: register_gauge_vec!(
+            "sensor_adc_full_scale",
+            "Largest raw reading of the sensor, from the resolution it reports",
+            &["device"]
+        )
+        .unwrap(),
+        firmware: register_gauge_vec!(
+            "sensor_firmware_info",
+            "Always 1, labeled with the firmware revision from the Device Information Service",
+            &["device", "firmware_revision"]
+        )
+        .unwrap(),
+        last_update: register_gauge_vec!(
+            "sensor_last_update_timestamp_seconds",
+            "Unix time the last reading was received, whether it was exported or not",
+            &["device"]
+        )
+        .unwrap(),
+        first_reading: register_histogram_vec!(
+            "sensor_first_reading_seconds",
+            "Time from starting the scan that found a sensor to its first decoded reading",
+            &["device"],
+            FIRST_READING_BUCKETS.to_vec()
+        )
+        .unwrap(),
+    };
+
+    if !args.address_allowlist.is_empty() {
+        info!(allowlist = ?args.address_allowlist, "Trying allowed addresses only");
+    } else if args.name_filter.is_empty() {
+        info!("Trying every peripheral, the name filter is empty");
+    } else {
+        info!(name_filter = %args.name_filter, "Trying peripherals named like the filter");
+    }
+    let context = Arc::new(Context {
+        calibration: MoistureCalibration::new(args.moisture_dry_raw, args.moisture_wet_raw),
+        scheduler: Mutex::new(Scheduler::new(&args.priorities)),
+        args,
+        metrics,
+        readings,
+        health,
+        known_peripherals: Mutex::new(HashMap::new()),
+        session_ended: Notify::new(),
+    });
+    // Sensors being served, by device.
+    let mut sessions: HashMap<String, JoinHandle<()>> = HashMap::new();
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+    let mut failed_cycles = 0;
+    let scan_forever = async {
+        loop {
+            // poor-man's supervision: only sensors whose session ended are picked up again.
+            sessions.retain(|_, session| !session.is_finished());
+            context.health.loop_alive();
+            let scanned = if cfg!(feature = "mock-ble") {
+                mock::start_sessions(&context, &mut sessions)
+            } else {
+                scan_and_subscribe(&adapter_list, &context, &mut sessions).await
+            };
+            let started = match scanned {
+                Ok(started) => {
+                    failed_cycles = 0;
+                    started
+                }
+                Err(err) => {
+                    warn!(%err, "Scan cycle failed");
+                    failed_cycles += 1;
+                    0
+                }
+            };
+            if failed_cycles >= ADAPTER_REFRESH_FAILURES {
+                failed_cycles = 0;
+                match find_adapters(adapter_index).await {
+                    Ok(adapters) => {
+                        info!(adapters = adapters.len(), "Refreshed the Bluetooth adapters");
+                        adapter_list = adapters;
+                    }
+                    Err(err) => warn!(%err, "Can't refresh the Bluetooth adapters"),
+                }
+            }
+            if started > 0 {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            info!(sessions = sessions.len(), ?delay, "Scanning again later");
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = context.session_ended.notified() => {}
+            }
+        }
+    };
+    tokio::select! {
+        _ = scan_forever => {}
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+    for session in sessions.values() {
+        session.abort();
+    }
+    disconnect_all(&adapter_list).await;
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Can't handle SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Disconnects from every connected peripheral, so the next run can connect
+/// cleanly. A dead adapter is given up on after a few seconds.
+async fn disconnect_all(adapter_list: &[Adapter]) {
+    for adapter in adapter_list.iter() {
+        for peripheral in adapter.peripherals().await.unwrap_or_default() {
+            if peripheral.is_connected().await.unwrap_or(false) {
+                let address = peripheral.address();
+                info!(%address, "Disconnecting");
+                if timeout(Duration::from_secs(5), peripheral.disconnect())
+                    .await
+                    .is_err()
+                {
+                    warn!(%address, "Disconnecting timed out");
+                }
+            }
+        }
+    }
+}
+
+/// Scans once and starts a session with the most overdue matching sensors not
+/// served yet, as many as `--max-sessions` leaves room for, returning how many
+/// were started. Fails when no adapter could scan.
+#[tracing::instrument(name = "scan", skip_all)]
+async fn scan_and_subscribe(
+    adapter_list: &[Adapter],
+    context: &Arc<Context>,
+    sessions: &mut HashMap<String, JoinHandle<()>>,
+) -> Result<usize, Box<dyn Error>> {
+    let args = &context.args;
+    if sessions.len() >= args.max_sessions() {
+        debug!(sessions = sessions.len(), "Every session slot is taken");
+        return Ok(0);
+    }
+    let mut started = reconnect_known(context, sessions).await;
+    if started > 0 {
+        // Sensors still missing are scanned for in the next cycle, right after.
+        return Ok(started);
+    }
+    let mut scanned = 0;
+    // Every adapter scans at the same time, e.g. a dongle per room.
+    let scan_started = Instant::now();
+    let scans = future::join_all(adapter_list.iter().map(|adapter| scan(adapter, args))).await;
+    // Peripherals of every adapter by device, with the adapter that found them.
+    let mut peripherals_by_device = Vec::new();
+    for (adapter, peripherals) in adapter_list.iter().zip(scans) {
+        let peripherals = match peripherals {
+            Ok(peripherals) => peripherals,
+            Err(err) => {
+                warn!(%err, "Can't scan BLE adapter for devices");
+                context.metrics.scan_failures.inc();
+                continue;
+            }
+        };
+        scanned += 1;
 
         if peripherals.is_empty() {
-            eprintln!("->>> BLE peripheral devices were not found, sorry. Exiting...");
+            warn!("No BLE peripherals found");
         } else {
-            // All peripheral devices in range.
-            for peripheral in peripherals.iter() {
+            // All peripheral devices in range, with whether they're advertising right now.
+            let mut found: Vec<(String, PlatformPeripheral, bool)> = Vec::new();
+            for peripheral in peripherals {
                 let properties = peripheral.properties().await?;
-                let is_connected = peripheral.is_connected().await?;
-                let local_name = properties
-                    .unwrap()
-                    .local_name
-                    .unwrap_or(String::from("(peripheral name unknown)"));
-                println!(
-                    "Peripheral {:?} is connected: {:?}",
-                    &local_name, is_connected
-                );
-                // Check if it's the peripheral we want.
-                if local_name.contains(PERIPHERAL_NAME_MATCH_FILTER) {
-                    println!("Found matching peripheral {:?}...", &local_name);
-                    if !is_connected {
-                        // Connect if we aren't already connected.
-                        if let Err(err) =
-                            timeout(Duration::from_secs(25), peripheral.connect()).await?
-                        {
-                            eprintln!("Error connecting to peripheral, skipping: {}", err);
-                            continue;
-                        }
+                let advertising = properties.as_ref().is_some_and(|p| p.rssi.is_some());
+                let name = properties.and_then(|properties| properties.local_name);
+                let device = device_id(args.device_label, peripheral.address(), name);
+                // A sensor rebooting with a new private address shows up next to the
+                // stale one the adapter remembers, serve it under its name still.
+                match found.iter_mut().find(|(known, _, _)| *known == device) {
+                    Some((_, known, known_advertising)) if advertising && !*known_advertising => {
+                        info!(
+                            %device,
+                            old = %known.address(),
+                            new = %peripheral.address(),
+                            "Sensor came back with another address"
+                        );
+                        *known = peripheral;
+                        *known_advertising = true;
                     }
-                    let is_connected =
-                        timeout(Duration::from_secs(25), peripheral.is_connected()).await??;
-                    println!(
-                        "Now connected ({:?}) to peripheral {:?}.",
-                        is_connected, &local_name
+                    Some(_) => {}
+                    None => found.push((device, peripheral, advertising)),
+                }
+            }
+            peripherals_by_device.extend(
+                found
+                    .into_iter()
+                    .map(|(device, peripheral, _)| (device, (adapter, peripheral))),
+            );
+        }
+    }
+    if scanned == 0 {
+        return Err("No Bluetooth adapter could scan".into());
+    }
+
+    // Most overdue first, a sensor seen by several adapters staying with the first one.
+    context.scheduler.lock().unwrap().order(&mut peripherals_by_device);
+    for (device, (adapter, peripheral)) in peripherals_by_device {
+        if sessions.contains_key(&device) {
+            continue;
+        }
+        // BlueZ reports no properties for a moment after discovering a device.
+        let Some(properties) = peripheral.properties().await? else {
+            debug!(address = %peripheral.address(), "No properties yet, skipping");
+            continue;
+        };
+        let local_name = properties
+            .local_name
+            .clone()
+            .unwrap_or(String::from("(peripheral name unknown)"));
+        // Check if it's the peripheral we want.
+        if args.matches(peripheral.address(), &local_name) {
+            let metrics = &context.metrics;
+            update_advertised_status(metrics, &device, &properties.manufacturer_data);
+            update_rssi(metrics, &device, properties.rssi);
+            if sessions.len() >= args.max_sessions() {
+                debug!(%device, "No free session slot, leaving it to a later turn");
+                continue;
+            }
+            info!(%device, rssi = ?properties.rssi, "Found matching peripheral");
+
+            let session =
+                start_session(context, adapter, device.clone(), peripheral, scan_started);
+            sessions.insert(device, session);
+            started += 1;
+        } else {
+            debug!(%local_name, "Skipping unknown peripheral");
+        }
+    }
+    Ok(started)
+}
+
+/// Starts a session with `peripheral`, found by a scan started at `scan_started`
+/// or reconnected to directly then.
+fn start_session(
+    context: &Arc<Context>,
+    adapter: &Adapter,
+    device: String,
+    peripheral: PlatformPeripheral,
+    scan_started: Instant,
+) -> JoinHandle<()> {
+    let context = Arc::clone(context);
+    let adapter = adapter.clone();
+    let span = info_span!(parent: None, "session", device = %device);
+    tokio::spawn(
+        async move {
+            match serve(&context, &adapter, &device, &peripheral, scan_started).await {
+                // It was reachable, so try it directly again next time.
+                Ok(()) => {
+                    context
+                        .known_peripherals
+                        .lock()
+                        .unwrap()
+                        .insert(device.clone(), (adapter, peripheral.id()));
+                }
+                Err(err) => warn!(%err, "Session failed"),
+            }
+            context
+                .metrics
+                .reconnects
+                .with_label_values(&[&device])
+                .inc();
+            context.scheduler.lock().unwrap().serviced(&device);
+            context.session_ended.notify_one();
+        }
+        .instrument(span),
+    )
+}
+
+/// Starts a session with every sensor served before that isn't served now,
+/// connecting by its identifier without a scan. Returns how many were started.
+///
+/// A sensor is forgotten when that fails, so the next scan finds it again.
+/// When there are more of them than free session slots none is started, the
+/// next scan lets the scheduler pick among them and the sensors waiting for a
+/// turn instead.
+async fn reconnect_known(
+    context: &Arc<Context>,
+    sessions: &mut HashMap<String, JoinHandle<()>>,
+) -> usize {
+    let free = context.args.max_sessions().saturating_sub(sessions.len());
+    let known: Vec<_> = {
+        let mut known_peripherals = context.known_peripherals.lock().unwrap();
+        known_peripherals.retain(|device, _| !sessions.contains_key(device));
+        if known_peripherals.len() > free {
+            return 0;
+        }
+        known_peripherals.drain().collect()
+    };
+    let mut started = 0;
+    for (device, (adapter, id)) in known {
+        match adapter.peripheral(&id).await {
+            Ok(peripheral) => {
+                info!(%device, "Reconnecting to a known peripheral");
+                let session =
+                    start_session(context, &adapter, device.clone(), peripheral, Instant::now());
+                sessions.insert(device, session);
+                started += 1;
+            }
+            Err(err) => debug!(%device, %err, "Known peripheral is gone, scanning for it"),
+        }
+    }
+    started
+}
+
+/// Scans with `adapter` for the scan duration, returning every peripheral it knows of.
+async fn scan(
+    adapter: &Adapter,
+    args: &ExportArgs,
+) -> Result<Vec<PlatformPeripheral>, Box<dyn Error>> {
+    info!("Scanning");
+    adapter.start_scan(scan_filter(args)).await?;
+    time::sleep(args.scan_duration()).await;
+    Ok(adapter.peripherals().await?)
+}
+
+/// Pause between two attempts to connect to a sensor.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Connects to `peripheral`, trying `--connect-attempts` times as many BLE
+/// stacks fail the first attempt right after a scan.
+async fn connect(
+    args: &ExportArgs,
+    peripheral: &PlatformPeripheral,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut attempt = 1;
+    loop {
+        let err: Box<dyn Error + Send + Sync> =
+            match timeout(args.ble_timeout(), peripheral.connect()).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(err)) => err.into(),
+                Err(elapsed) => elapsed.into(),
+            };
+        if attempt >= args.connect_attempts {
+            return Err(err);
+        }
+        warn!(attempt, %err, "Can't connect, retrying");
+        attempt += 1;
+        time::sleep(CONNECT_RETRY_DELAY).await;
+    }
+}
+
+/// Connects to a sensor and exports its readings until it goes quiet, the
+/// connection breaks or its service time is over. `scan_started` is when the
+/// scan that found it started.
+async fn serve(
+    context: &Context,
+    adapter: &Adapter,
+    device: &str,
+    peripheral: &PlatformPeripheral,
+    scan_started: Instant,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = &context.args;
+    let metrics = &context.metrics;
+    if !peripheral.is_connected().await? {
+        // Connect if we aren't already connected.
+        connect(args, peripheral).await?;
+    }
+    let is_connected = timeout(args.ble_timeout(), peripheral.is_connected()).await??;
+    if !is_connected {
+        warn!("Not connected after connecting");
+        return Ok(());
+    }
+    info!("Connected");
+
+    debug!("Discovering services");
+    peripheral.discover_services().await?;
+    let mut subscribed = false;
+    // The reading characteristic when polling it.
+    let mut polled = None;
+    let mut calibrating = false;
+    for characteristic in peripheral.characteristics() {
+        debug!(
+            uuid = %characteristic.uuid,
+            properties = ?characteristic.properties,
+            "Checking characteristic"
+        );
+        // Subscribe to notifications from the characteristics with the selected
+        // UUIDs.
+        if (characteristic.uuid == args.notify_uuid
+            || characteristic.uuid == STATUS_CHARACTERISTIC_UUID
+            || characteristic.uuid == NOISE_CHARACTERISTIC_UUID
+            || args.notified_metric(characteristic.uuid).is_some())
+            && characteristic.properties.contains(CharPropFlags::NOTIFY)
+        {
+            debug!(uuid = %characteristic.uuid, "Subscribing to notifications");
+            let _ = timeout(args.ble_timeout(), peripheral.subscribe(&characteristic)).await?;
+            subscribed |= characteristic.uuid == args.notify_uuid;
+        }
+        if characteristic.uuid == args.notify_uuid
+            && characteristic.properties.contains(CharPropFlags::READ)
+            && args.poll_interval_secs.is_some()
+        {
+            polled = Some(characteristic.clone());
+        }
+        // Alerts are indicated, btleplug confirms them on our behalf.
+        if characteristic.uuid == ALERT_CHARACTERISTIC_UUID
+            && characteristic.properties.contains(CharPropFlags::INDICATE)
+        {
+            debug!(uuid = %characteristic.uuid, "Subscribing to indications");
+            let _ = timeout(args.ble_timeout(), peripheral.subscribe(&characteristic)).await?;
+        }
+        // Status is only notified on change, so start from its current value.
+        if characteristic.uuid == STATUS_CHARACTERISTIC_UUID {
+            let status = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+            calibrating = update_status(metrics, device, &status);
+        }
+        // Diagnostics are read once per connection.
+        let diagnostic = match characteristic.uuid {
+            BOOT_COUNT_CHARACTERISTIC_UUID => Some(&metrics.boot_count),
+            FREE_RAM_CHARACTERISTIC_UUID => Some(&metrics.free_ram),
+            _ => None,
+        };
+        if let Some(gauge) = diagnostic {
+            let value = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+            if let Ok(value) = <[u8; 4]>::try_from(value) {
+                gauge
+                    .with_label_values(&[device])
+                    .set(u32::from_le_bytes(value).into());
+            }
+        }
+        if characteristic.uuid == BATTERY_LEVEL_CHARACTERISTIC_UUID
+            && characteristic.properties.contains(CharPropFlags::READ)
+        {
+            let value = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+            if let Some(&level) = value.first() {
+                metrics.battery.with_label_values(&[device]).set(level.into());
+            }
+        }
+        if characteristic.uuid == ADC_RESOLUTION_CHARACTERISTIC_UUID {
+            let value = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+            let full_scale = value.first().and_then(|&bits| calibration::full_scale(bits));
+            if let Some(full_scale) = full_scale {
+                debug!(full_scale, "ADC full scale");
+                metrics
+                    .adc_full_scale
+                    .with_label_values(&[device])
+                    .set(full_scale.into());
+                // The calibration points are raw readings, so they can't exceed it.
+                if let Some(calibration) = context.calibration {
+                    if !calibration.fits(full_scale) {
+                        warn!(full_scale, "Calibration points beyond the sensor's full scale");
+                    }
+                }
+            }
+        }
+        if characteristic.uuid == FIRMWARE_REVISION_CHARACTERISTIC_UUID {
+            let value = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+            let revision = String::from_utf8_lossy(&value);
+            let revision = revision.trim_end_matches('\0');
+            info!(revision, "Firmware");
+            metrics
+                .firmware
+                .with_label_values(&[device, revision])
+                .set(1.0);
+        }
+    }
+
+    if subscribed || polled.is_some() {
+        info!(subscribed, polled = polled.is_some(), "Listening");
+        let _connected = Connected::new(&metrics.connected, device);
+        let mut notification_stream = peripheral.notifications().await?;
+        // Notices a dropped connection right away instead of on the timeout.
+        let disconnected = disconnected(adapter.events().await?, peripheral.id());
+        tokio::pin!(disconnected);
+        let session_over = session_over(args.max_session());
+        tokio::pin!(session_over);
+        // Whether the device notified a fresh reading since we connected.
+        let mut fresh = !args.ignore_first_after_connect;
+        // Whether the time to the first reading was observed yet.
+        let mut first_reading = true;
+        let mut ewma = args.ewma();
+        let deadline = args
+            .service_time
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let mut poll = polled
+            .as_ref()
+            .and(args.poll_interval())
+            .map(|period| time::interval_at(time::Instant::now() + period, period));
+        // When polling, a reading comes in at least every poll interval.
+        let quiet_after = args.ble_timeout() + args.poll_interval().unwrap_or_default();
+        // Process while the BLE connection is not broken or stopped,
+        // and the sensor's service time is not over.
+        loop {
+            let data = tokio::select! {
+                data = timeout(
+                    schedule::next_wait(deadline, quiet_after),
+                    notification_stream.next(),
+                ) => match data {
+                    Ok(Some(data)) => data,
+                    _ => break,
+                },
+                characteristic = poll_tick(&mut poll, &polled) => {
+                    let value = timeout(args.ble_timeout(), peripheral.read(characteristic)).await??;
+                    debug!("Polled the reading");
+                    ValueNotification {
+                        uuid: characteristic.uuid,
+                        value,
+                    }
+                },
+                _ = &mut disconnected => {
+                    info!("Adapter reported a disconnect");
+                    break;
+                }
+                _ = &mut session_over => {
+                    info!("Maximum session duration reached, reconnecting");
+                    break;
+                }
+            };
+            if data.uuid == STATUS_CHARACTERISTIC_UUID {
+                calibrating = update_status(metrics, device, &data.value);
+                continue;
+            }
+            if data.uuid == NOISE_CHARACTERISTIC_UUID {
+                if let Ok(noise) = <[u8; 2]>::try_from(data.value) {
+                    update_noise(
+                        metrics,
+                        device,
+                        u16::from_le_bytes(noise).into(),
+                        args.poor_contact_noise,
                     );
-                    if is_connected {
-                        println!("Discover peripheral {:?} services...", local_name);
-                        peripheral.discover_services().await?;
-                        for characteristic in peripheral.characteristics() {
-                            println!("Checking characteristic {:?}", characteristic);
-                            // Subscribe to notifications from the characteristic with the selected
-                            // UUID.
-                            if characteristic.uuid == NOTIFY_CHARACTERISTIC_UUID
-                                && characteristic.properties.contains(CharPropFlags::NOTIFY)
-                            {
-                                println!("Subscribing to characteristic {:?}", characteristic.uuid);
-                                let _ = timeout(
-                                    Duration::from_secs(25),
-                                    peripheral.subscribe(&characteristic),
-                                )
-                                .await?;
-
-                                let mut notification_stream = peripheral.notifications().await?;
-                                // Process while the BLE connection is not broken or stopped.
-                                while let Ok(Some(data)) =
-                                    timeout(Duration::from_secs(25), notification_stream.next())
-                                        .await
-                                {
-                                    let metric =
-                                        ((data.value[1] as u16) << 8) | data.value[0] as u16;
-                                    println!(
-                                        "Received data from {:?} [{:?}]: {:?}",
-                                        local_name, data.uuid, metric
-                                    );
-                                    gauge.set(metric.into());
-                                }
-                            }
+                }
+                continue;
+            }
+            if data.uuid == ALERT_CHARACTERISTIC_UUID {
+                metrics.alerts.handle(device, &data.value);
+                continue;
+            }
+            if let Some(metric) = args.notified_metric(data.uuid) {
+                match decode_signed(&data.value, args.moisture_endian) {
+                    Some(value) => {
+                        debug!(metric, value, "Received reading");
+                        mark_reading(metrics, device);
+                        if !calibrating {
+                            export_notified(context, device, metric, value.into()).await?;
                         }
-                        println!("Disconnecting from peripheral {:?}...", local_name);
-                        let _ = timeout(Duration::from_secs(25), peripheral.disconnect()).await?;
                     }
-                } else {
-                    println!("Skipping unknown peripheral {:?}", local_name);
+                    None => {
+                        warn!(metric, payload = ?data.value, "Malformed reading, skipping");
+                        metrics.malformed_packets.with_label_values(&[device]).inc();
+                    }
                 }
+                continue;
+            }
+
+            // A truncated packet must not tear the connection down.
+            let metric = match decode_moisture(&data.value, args.moisture_endian) {
+                Some(metric) => metric,
+                None => {
+                    warn!(payload = ?data.value, "Malformed reading, skipping");
+                    metrics.malformed_packets.with_label_values(&[device]).inc();
+                    continue;
+                }
+            };
+            info!(uuid = %data.uuid, raw_value = metric, "Received reading");
+            mark_reading(metrics, device);
+            if first_reading {
+                first_reading = false;
+                let latency = scan_started.elapsed();
+                debug!(?latency, "First reading since the scan started");
+                metrics
+                    .first_reading
+                    .with_label_values(&[device])
+                    .observe(latency.as_secs_f64());
+            }
+            // Some platforms keep updating the RSSI while connected.
+            if let Ok(Some(properties)) = peripheral.properties().await {
+                update_rssi(metrics, device, properties.rssi);
+            }
+            if !fresh {
+                debug!("Ignoring first reading after connect");
+                fresh = true;
+                continue;
+            }
+            // Readings taken while calibrating are transient, don't export them.
+            if !calibrating {
+                export_reading(context, device, metric, &mut ewma).await?;
             }
         }
     }
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await?;
+    info!("Disconnected");
     Ok(())
 }
+
+/// Stamps the decoding of a reading notification of `device` with the current time.
+fn mark_reading(metrics: &Metrics, device: &str) {
+    if let Ok(since_epoch) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        metrics
+            .last_update
+            .with_label_values(&[device])
+            .set(since_epoch.as_secs_f64());
+    }
+}
+
+/// Hands a decoded reading of `device` over to the sinks, unless it's
+/// implausible, along with its percent and smoothed values when enabled.
+async fn export_reading(
+    context: &Context,
+    device: &str,
+    raw: u16,
+    ewma: &mut Option<Ewma>,
+) -> Result<(), mpsc::error::SendError<Reading>> {
+    let reading = Reading {
+        device: device.to_string(),
+        metric: "soil_moisture",
+        value: raw.into(),
+        timestamp: SystemTime::now(),
+    };
+    context.health.reading_seen();
+    if !context.metrics.plausibility.check(&reading) {
+        return Ok(());
+    }
+    let readings = &context.readings;
+    if let Some(calibration) = context.calibration {
+        readings
+            .send(Reading {
+                metric: "soil_moisture_percent",
+                value: calibration.percent(raw),
+                ..reading.clone()
+            })
+            .await?;
+    }
+    if let Some(ewma) = ewma.as_mut() {
+        readings
+            .send(Reading {
+                metric: "soil_moisture_smoothed",
+                value: ewma.update(reading.value),
+                ..reading.clone()
+            })
+            .await?;
+    }
+    readings.send(reading).await
+}
+
+/// Hands a reading of a `--notify-metric` characteristic over to the sinks, unless it's implausible.
+async fn export_notified(
+    context: &Context,
+    device: &str,
+    metric: &'static str,
+    value: f64,
+) -> Result<(), mpsc::error::SendError<Reading>> {
+    let reading = Reading {
+        device: device.to_string(),
+        metric,
+        value,
+        timestamp: SystemTime::now(),
+    };
+    if !context.metrics.plausibility.check(&reading) {
+        return Ok(());
+    }
+    context.readings.send(reading).await
+}
+
+/// Completes once a session lasted `max_session`, never without one.
+async fn session_over(max_session: Option<Duration>) {
+    match max_session {
+        Some(max_session) => time::sleep(max_session).await,
+        None => future::pending().await,
+    }
+}
+
+/// Waits for the next poll of the `polled` characteristic, forever when not polling.
+async fn poll_tick<'a>(
+    poll: &mut Option<time::Interval>,
+    polled: &'a Option<Characteristic>,
+) -> &'a Characteristic {
+    match (poll, polled) {
+        (Some(poll), Some(polled)) => {
+            poll.tick().await;
+            polled
+        }
+        _ => futures::future::pending().await,
+    }
+}
+
+/// Resolves once the adapter reports the peripheral `id` disconnected, never if
+/// its events end.
+async fn disconnected(mut events: impl Stream<Item = CentralEvent> + Unpin, id: PeripheralId) {
+    while let Some(event) = events.next().await {
+        if matches!(event, CentralEvent::DeviceDisconnected(disconnected) if disconnected == id) {
+            return;
+        }
+    }
+    futures::future::pending().await
+}
+
+/// Parses a characteristic UUID, either in its 16-bit short form like `babe` or a full 128-bit one.
+fn parse_uuid(s: &str) -> Result<Uuid, String> {
+    let short = s.strip_prefix("0x").unwrap_or(s);
+    if short.len() == 4 {
+        if let Ok(uuid) = u16::from_str_radix(short, 16) {
+            return Ok(uuid_from_u16(uuid));
+        }
+    }
+    Uuid::parse_str(s).map_err(|_| {
+        format!(
+            "invalid UUID {:?}, expected a 16-bit one like babe or a full 128-bit one",
+            s
+        )
+    })
+}
+
+/// Scan filter of the `--scan-service` UUIDs, no UUIDs making an unfiltered scan.
+fn scan_filter(args: &ExportArgs) -> ScanFilter {
+    ScanFilter {
+        services: args.scan_services.clone(),
+    }
+}
+
+/// Label value identifying a sensor, its address when it doesn't advertise a name.
+fn device_id(label: DeviceLabel, address: BDAddr, local_name: Option<String>) -> String {
+    match (label, local_name) {
+        (DeviceLabel::Name, Some(local_name)) => local_name,
+        _ => address.to_string(),
+    }
+}
+
+/// Exports the sensor status byte, returning whether the sensor is calibrating.
+fn update_status(metrics: &Metrics, device: &str, status: &[u8]) -> bool {
+    let flags = status.first().copied().unwrap_or_default();
+    let calibrating = flags & FLAG_CALIBRATING != 0;
+    let low_battery = flags & FLAG_LOW_BATTERY != 0;
+    let probe_dropped = flags & FLAG_PROBE_DROPPED != 0;
+    info!(device, calibrating, low_battery, probe_dropped, "Sensor status");
+    set_flag(&metrics.calibrating, device, calibrating);
+    set_flag(&metrics.low_battery, device, low_battery);
+    set_flag(&metrics.probe_dropped, device, probe_dropped);
+    calibrating
+}
+
+/// Exports a sensor's signal noise, flagging a poor contact above `threshold`.
+fn update_noise(metrics: &Metrics, device: &str, noise: f64, threshold: Option<f64>) {
+    metrics.noise.with_label_values(&[device]).set(noise);
+    if let Some(threshold) = threshold {
+        let poor_contact = noise > threshold;
+        if poor_contact {
+            warn!(device, noise, "Poor probe contact");
+        }
+        set_flag(&metrics.poor_contact, device, poor_contact);
+    }
+}
+
+/// Exports a sensor's signal strength, left as is when the adapter doesn't report it.
+fn update_rssi(metrics: &Metrics, device: &str, rssi: Option<i16>) {
+    if let Some(rssi) = rssi {
+        metrics.rssi.with_label_values(&[device]).set(rssi.into());
+    }
+}
+
+/// Exports the status byte advertised as manufacturer data, which is
+/// available without connecting to the sensor.
+fn update_advertised_status(
+    metrics: &Metrics,
+    device: &str,
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+) {
+    if let Some(flags) = manufacturer_data
+        .get(&MANUFACTURER_ID)
+        .and_then(|data| data.first())
+    {
+        let low_battery = flags & FLAG_LOW_BATTERY != 0;
+        debug!(device, low_battery, "Advertised status");
+        set_flag(&metrics.low_battery, device, low_battery);
+    }
+}
+
+/// Sets `sensor_connected` of a device while alive. Dropping it clears the
+/// flag, also when an error ends the connection early.
+struct Connected<'a> {
+    gauge: &'a GaugeVec,
+    device: &'a str,
+}
+
+impl<'a> Connected<'a> {
+    fn new(gauge: &'a GaugeVec, device: &'a str) -> Self {
+        set_flag(gauge, device, true);
+        Connected { gauge, device }
+    }
+}
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        set_flag(self.gauge, self.device, false);
+    }
+}
+
+fn set_flag(gauge: &GaugeVec, device: &str, value: bool) {
+    gauge
+        .with_label_values(&[device])
+        .set(if value { 1.0 } else { 0.0 });
+}
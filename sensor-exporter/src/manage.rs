@@ -0,0 +1,328 @@
+//! Remote management of sensors through the firmware's control characteristic.
+
+use crate::ExportArgs;
+use btleplug::api::{bleuuid::uuid_from_u16, Characteristic, Peripheral as _, WriteType};
+use btleplug::platform::{Adapter, Peripheral};
+use clap::{Args, Subcommand};
+use futures::StreamExt;
+use sensor_exporter::{decode_moisture, Endian};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+/// UUID of the firmware's control characteristic.
+const CONTROL_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xc0d1);
+/// Every control command is an opcode followed by these magic bytes.
+const CONTROL_MAGIC: [u8; 3] = *b"MB!";
+/// UUID of the firmware's configuration blob characteristic.
+const CONFIG_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xc0d2);
+/// Configuration blob layout version this exporter can restore, and the blob's size in it.
+const CONFIG_VERSION: u8 = 4;
+const CONFIG_SIZE: usize = 18;
+/// UUID of the characteristic notifying readings.
+const READING_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbabe);
+/// UUID of the characteristic asking the firmware for a reading right away.
+const READ_NOW_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab8);
+
+/// Control command rebooting the sensor.
+const CMD_REBOOT: u8 = 0x01;
+/// Control command erasing the persisted settings and rebooting the sensor.
+const CMD_FACTORY_RESET: u8 = 0x02;
+/// Control command rebooting the sensor into a DFU bootloader.
+const CMD_ENTER_DFU: u8 = 0x03;
+/// Control command letting a new central bond with a `secure` sensor for a minute.
+const CMD_OPEN_PAIRING: u8 = 0x40;
+
+/// Control command status codes reported back by the firmware.
+const STATUS_OK: u8 = 0x00;
+const STATUS_UNSUPPORTED: u8 = 0x01;
+const STATUS_BAD_MAGIC: u8 = 0x02;
+const STATUS_INVALID_STATE: u8 = 0x03;
+
+#[derive(Args)]
+pub struct ManageArgs {
+    /// Local name (or a part of it) of the sensor to manage.
+    #[arg(long)]
+    device: String,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Action {
+    /// Reboot the sensor.
+    Reboot,
+    /// Erase persisted settings and reboot the sensor.
+    FactoryReset,
+    /// Reboot the sensor into a DFU bootloader. The sensor firmware takes
+    /// updates while running instead, see `update`.
+    EnterDfu,
+    /// Write the sensor's configuration blob to stdout, e.g. `dump-config > cfg.bin`.
+    DumpConfig,
+    /// Write a configuration blob from stdin to the sensor, e.g. `restore-config < cfg.bin`.
+    RestoreConfig,
+    /// Let a new central bond with a sensor built with the `secure` feature for a minute,
+    /// replacing the bonded one.
+    OpenPairing,
+    /// Have the sensor sample right away and print the raw reading, e.g. after watering.
+    ReadNow,
+    /// Upload a firmware image, reboot into it and confirm it once it's verified.
+    Update {
+        /// Raw binary of the firmware image, e.g. from `cargo objcopy --features ota -- -O binary`.
+        image: PathBuf,
+        /// Only confirm the image if the sensor reports this firmware version.
+        #[arg(long)]
+        expect_version: Option<String>,
+    },
+}
+
+/// Connects to the sensor named in `manage`, sends the command and disconnects.
+/// Scans and BLE operations take as long as the exporter's `args` allow.
+pub async fn run(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    manage: ManageArgs,
+) -> Result<(), Box<dyn Error>> {
+    // Updates reconnect on their own.
+    if let Action::Update {
+        image,
+        expect_version,
+    } = &manage.action
+    {
+        return crate::update::run(
+            adapter_list,
+            args,
+            &manage.device,
+            image,
+            expect_version.as_deref(),
+        )
+        .await;
+    }
+    // Read the blob first, so a bad file fails before scanning.
+    let blob = match manage.action {
+        Action::RestoreConfig => Some(read_config_blob()?),
+        _ => None,
+    };
+    let peripheral = find_peripheral(adapter_list, args, &manage.device)
+        .await?
+        .ok_or_else(|| format!("Sensor {:?} was not found", manage.device))?;
+
+    if !peripheral.is_connected().await? {
+        crate::connect(args, &peripheral).await.map_err(|err| err.to_string())?;
+    }
+    let result = match manage.action {
+        Action::Reboot => send_command(args, &peripheral, "Reboot", CMD_REBOOT).await,
+        Action::FactoryReset => {
+            send_command(args, &peripheral, "FactoryReset", CMD_FACTORY_RESET).await
+        }
+        Action::EnterDfu => send_command(args, &peripheral, "EnterDfu", CMD_ENTER_DFU).await,
+        Action::DumpConfig => dump_config(args, &peripheral).await,
+        Action::RestoreConfig => {
+            restore_config(args, &peripheral, &blob.unwrap_or_default()).await
+        }
+        Action::OpenPairing => {
+            send_command(args, &peripheral, "OpenPairing", CMD_OPEN_PAIRING).await
+        }
+        Action::ReadNow => read_now(args, &peripheral).await,
+        Action::Update { .. } => unreachable!("handled before connecting"),
+    };
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await;
+    result
+}
+
+/// Scans every adapter like the exporter does for a peripheral whose local name contains `name`.
+pub async fn find_peripheral(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    name: &str,
+) -> Result<Option<Peripheral>, Box<dyn Error>> {
+    for adapter in adapter_list.iter() {
+        for peripheral in crate::scan(adapter, args).await? {
+            let local_name = peripheral
+                .properties()
+                .await?
+                .and_then(|properties| properties.local_name);
+            if local_name.is_some_and(|local_name| local_name.contains(name)) {
+                return Ok(Some(peripheral));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the characteristic `uuid`, failing with `missing` when the firmware lacks it.
+pub async fn find_characteristic(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    uuid: Uuid,
+    missing: &str,
+) -> Result<Characteristic, Box<dyn Error>> {
+    timeout(args.ble_timeout(), peripheral.discover_services()).await??;
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|characteristic| characteristic.uuid == uuid)
+        .ok_or_else(|| missing.into())
+}
+
+/// Sends the control command `opcode`, named `name` in messages, and checks its status.
+pub async fn send_command(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    name: &str,
+    opcode: u8,
+) -> Result<(), Box<dyn Error>> {
+    let characteristic = find_characteristic(
+        args,
+        peripheral,
+        CONTROL_CHARACTERISTIC_UUID,
+        "Sensor firmware does not support remote management (no control characteristic)",
+    )
+    .await?;
+
+    let mut command = vec![opcode];
+    command.extend_from_slice(&CONTROL_MAGIC);
+    timeout(
+        args.ble_timeout(),
+        peripheral.write(&characteristic, &command, WriteType::WithResponse),
+    )
+    .await??;
+
+    let response = match timeout(args.ble_timeout(), peripheral.read(&characteristic)).await {
+        Ok(Ok(response)) => response,
+        // A rebooting sensor may already be gone by the time we ask for the status.
+        _ if opcode == CMD_REBOOT => {
+            println!("{} sent, sensor disconnected.", name);
+            return Ok(());
+        }
+        Ok(Err(err)) => {
+            return Err(format!("{} sent, but its status can't be read: {}", name, err).into())
+        }
+        Err(_) => return Err(format!("{} sent, but its status timed out", name).into()),
+    };
+
+    match response.as_slice() {
+        [status_opcode, STATUS_OK, ..] if *status_opcode == opcode => {
+            println!("{} confirmed by sensor.", name);
+            Ok(())
+        }
+        [status_opcode, STATUS_UNSUPPORTED, ..] if *status_opcode == opcode => {
+            Err(format!("{} is not supported by the sensor firmware", name).into())
+        }
+        [status_opcode, STATUS_BAD_MAGIC, ..] if *status_opcode == opcode => {
+            Err(format!("{} was rejected by the sensor (bad magic)", name).into())
+        }
+        [status_opcode, STATUS_INVALID_STATE, ..] if *status_opcode == opcode => {
+            Err(format!("{} is not possible in the sensor's current state", name).into())
+        }
+        _ => Err(format!("Unexpected control response {:02x?}", response).into()),
+    }
+}
+
+/// Reads the configuration blob and writes it to stdout as is.
+async fn dump_config(args: &ExportArgs, peripheral: &Peripheral) -> Result<(), Box<dyn Error>> {
+    let characteristic = find_characteristic(
+        args,
+        peripheral,
+        CONFIG_CHARACTERISTIC_UUID,
+        "Sensor firmware does not support configuration backup (no config characteristic)",
+    )
+    .await?;
+
+    let blob = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+    let version = *blob.first().ok_or("Sensor returned an empty configuration")?;
+    io::stdout().write_all(&blob)?;
+    // Stdout holds the blob, so report on stderr.
+    eprintln!("Dumped configuration version {} ({} bytes).", version, blob.len());
+    Ok(())
+}
+
+/// Reads a configuration blob from stdin, refusing other layout versions than `CONFIG_VERSION`.
+fn read_config_blob() -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut blob = Vec::new();
+    io::stdin().read_to_end(&mut blob)?;
+    match blob.first() {
+        None => Err("No configuration on stdin".into()),
+        Some(&version) if version != CONFIG_VERSION => Err(format!(
+            "Configuration version {} is not supported, expected version {}",
+            version, CONFIG_VERSION
+        )
+        .into()),
+        Some(_) if blob.len() != CONFIG_SIZE => Err(format!(
+            "Configuration is {} bytes long, expected {} bytes",
+            blob.len(),
+            CONFIG_SIZE
+        )
+        .into()),
+        Some(_) => Ok(blob),
+    }
+}
+
+/// Writes a configuration blob to the sensor and checks that it was taken over.
+async fn restore_config(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    blob: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let characteristic = find_characteristic(
+        args,
+        peripheral,
+        CONFIG_CHARACTERISTIC_UUID,
+        "Sensor firmware does not support configuration backup (no config characteristic)",
+    )
+    .await?;
+
+    timeout(
+        args.ble_timeout(),
+        peripheral.write(&characteristic, blob, WriteType::WithResponse),
+    )
+    .await??;
+    // The firmware keeps its current configuration when rejecting a blob.
+    let current = timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??;
+    if current != blob {
+        return Err("Sensor rejected the configuration".into());
+    }
+    println!("Configuration restored.");
+    Ok(())
+}
+
+/// Asks the sensor for a reading right away and prints the next one notified.
+async fn read_now(args: &ExportArgs, peripheral: &Peripheral) -> Result<(), Box<dyn Error>> {
+    let trigger = find_characteristic(
+        args,
+        peripheral,
+        READ_NOW_CHARACTERISTIC_UUID,
+        "Sensor firmware does not support on-demand readings (no read-now characteristic)",
+    )
+    .await?;
+    let reading = find_characteristic(
+        args,
+        peripheral,
+        READING_CHARACTERISTIC_UUID,
+        "Sensor firmware has no reading characteristic",
+    )
+    .await?;
+
+    // Listen before subscribing, the sensor samples as soon as notifications are enabled.
+    let mut notifications = peripheral.notifications().await?;
+    timeout(args.ble_timeout(), peripheral.subscribe(&reading)).await??;
+    timeout(
+        args.ble_timeout(),
+        peripheral.write(&trigger, &[1], WriteType::WithResponse),
+    )
+    .await??;
+    let raw = timeout(args.ble_timeout(), async {
+        while let Some(notification) = notifications.next().await {
+            if notification.uuid == READING_CHARACTERISTIC_UUID {
+                return decode_moisture(&notification.value, Endian::Little);
+            }
+        }
+        None
+    })
+    .await?
+    .ok_or("Sensor sent no valid reading")?;
+    println!("{}", raw);
+    Ok(())
+}
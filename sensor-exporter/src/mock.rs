@@ -0,0 +1,68 @@
+//! Synthetic sensors standing in for Bluetooth ones with the `mock-ble`
+//! feature, so the exporter can be run and tested without hardware.
+//!
+//! Every mock sensor gets a session like a real one, notifying a sine wave of
+//! raw readings that goes through the same pipeline as decoded notifications.
+
+use crate::{export_reading, mark_reading, Connected, Context};
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::TAU;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, info_span, warn, Instrument};
+
+/// Devices of the mock sensors.
+const DEVICES: [&str; 2] = ["MockBit-1", "MockBit-2"];
+/// Time between two readings of a mock sensor.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Period of the sine wave of readings.
+const PERIOD: Duration = Duration::from_secs(60);
+
+/// Starts a session with every mock sensor not served yet, returning how many were started.
+pub fn start_sessions(
+    context: &Arc<Context>,
+    sessions: &mut HashMap<String, JoinHandle<()>>,
+) -> Result<usize, Box<dyn Error>> {
+    let mut started = 0;
+    for (index, device) in DEVICES.into_iter().enumerate() {
+        if sessions.contains_key(device) {
+            continue;
+        }
+        if sessions.len() >= context.args.max_sessions() {
+            break;
+        }
+        let context = Arc::clone(context);
+        let span = info_span!(parent: None, "session", device = %device);
+        let session = tokio::spawn(
+            async move {
+                if let Err(err) = serve(&context, device, index).await {
+                    warn!(%err, "Session failed");
+                }
+                context.session_ended.notify_one();
+            }
+            .instrument(span),
+        );
+        sessions.insert(device.to_string(), session);
+        started += 1;
+    }
+    Ok(started)
+}
+
+/// Exports the readings of a mock sensor, its wave shifted by its `index`.
+async fn serve(context: &Context, device: &str, index: usize) -> Result<(), Box<dyn Error>> {
+    info!("Mock sensor connected");
+    let _connected = Connected::new(&context.metrics.connected, device);
+    let mut ewma = context.args.ewma();
+    let start = Instant::now();
+    let mut interval = time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        let phase = start.elapsed().as_secs_f64() / PERIOD.as_secs_f64() + index as f64 / 4.0;
+        let raw = (2048.0 + 1000.0 * (TAU * phase).sin()) as u16;
+        mark_reading(&context.metrics, device);
+        export_reading(context, device, raw, &mut ewma).await?;
+    }
+}
@@ -0,0 +1,116 @@
+//! Additional characteristics notifying readings of their own, e.g. a
+//! temperature next to the soil moisture, each routed to its own metric.
+
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Prefixes of the exporter's own metrics, which notified metrics can't take.
+const RESERVED_PREFIXES: [&str; 2] = ["soil_moisture", "sensor_"];
+
+/// A characteristic notifying readings of `metric`, written as `uuid=metric`.
+#[derive(Clone, Debug)]
+pub struct NotifiedMetric {
+    pub uuid: Uuid,
+    /// Name of the gauge, leaked as readings carry `'static` metric names.
+    pub metric: &'static str,
+}
+
+impl FromStr for NotifiedMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid notified metric {:?}, expected uuid=metric", s);
+        let (uuid, metric) = s.split_once('=').ok_or_else(invalid)?;
+        let uuid = crate::parse_uuid(uuid.trim())?;
+        let metric = metric.trim();
+        if !is_metric_name(metric) {
+            return Err(format!(
+                "invalid metric name {:?}, expected letters, digits and underscores",
+                metric
+            ));
+        }
+        if RESERVED_PREFIXES.iter().any(|prefix| metric.starts_with(prefix)) {
+            return Err(format!(
+                "metric name {:?} is taken by the exporter's own soil_moisture* and sensor_* metrics",
+                metric
+            ));
+        }
+        Ok(NotifiedMetric {
+            uuid,
+            metric: Box::leak(metric.to_string().into_boxed_str()),
+        })
+    }
+}
+
+impl fmt::Display for NotifiedMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.uuid, self.metric)
+    }
+}
+
+/// Checks that no two notified metrics share a characteristic or a name.
+pub fn check_distinct(notified: &[NotifiedMetric]) -> Result<(), String> {
+    for (index, first) in notified.iter().enumerate() {
+        for second in &notified[index + 1..] {
+            if first.uuid == second.uuid {
+                return Err(format!("characteristic {} is given two notified metrics", first.uuid));
+            }
+            if first.metric == second.metric {
+                return Err(format!("metric {} is given to two characteristics", first.metric));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` is a valid Prometheus metric name.
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<NotifiedMetric, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_uuid_and_metric() {
+        let notified = parse("babc=soil_temperature").unwrap();
+        assert_eq!(notified.uuid, crate::parse_uuid("babc").unwrap());
+        assert_eq!(notified.metric, "soil_temperature");
+    }
+
+    #[test]
+    fn rejects_invalid_and_reserved_names() {
+        for s in [
+            "babc",
+            "babc=",
+            "babc=1st",
+            "babc=soil-temperature",
+            "babc=soil_moisture",
+            "babc=soil_moisture_percent",
+            "babc=sensor_connected",
+        ] {
+            assert!(parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let temperature = parse("babc=soil_temperature").unwrap();
+        let humidity = parse("babf=air_humidity").unwrap();
+        assert!(check_distinct(&[temperature.clone(), humidity.clone()]).is_ok());
+        let same_uuid = parse("babc=air_humidity").unwrap();
+        assert!(check_distinct(&[temperature.clone(), same_uuid]).is_err());
+        let same_metric = parse("bac0=soil_temperature").unwrap();
+        assert!(check_distinct(&[temperature, humidity, same_metric]).is_err());
+    }
+}
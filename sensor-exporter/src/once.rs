@@ -0,0 +1,88 @@
+//! One-shot read of a sensor's current soil moisture, for scripts and cron jobs.
+
+use crate::manage::find_characteristic;
+use crate::{connect, scan, ExportArgs};
+use btleplug::api::{bleuuid::uuid_from_u16, CharPropFlags, Peripheral as _, WriteType};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::StreamExt;
+use sensor_exporter::decode_moisture;
+use std::error::Error;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+/// UUID of the characteristic asking the firmware for a reading right away.
+const READ_NOW_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab8);
+
+/// Reads the current value of the first sensor matching the exporter's options.
+pub async fn run(adapter_list: &[Adapter], args: &ExportArgs) -> Result<u16, Box<dyn Error>> {
+    let peripheral = find_sensor(adapter_list, args)
+        .await?
+        .ok_or("No matching sensor found within the scan window")?;
+    if !peripheral.is_connected().await? {
+        connect(args, &peripheral).await.map_err(|err| err.to_string())?;
+    }
+    let result = read(&peripheral, args).await;
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await;
+    result
+}
+
+/// Scans like the exporter does and returns the first sensor it would serve.
+async fn find_sensor(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+) -> Result<Option<Peripheral>, Box<dyn Error>> {
+    for adapter in adapter_list {
+        for peripheral in scan(adapter, args).await? {
+            let Some(properties) = peripheral.properties().await? else {
+                continue;
+            };
+            let local_name = properties.local_name.unwrap_or_default();
+            if args.matches(peripheral.address(), &local_name) {
+                return Ok(Some(peripheral));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Waits for a fresh notification of the reading, asking the sensor for one
+/// right away when it can. Only reads the characteristic when it doesn't notify,
+/// as the firmware's readable value is stale until a client subscribes.
+async fn read(peripheral: &Peripheral, args: &ExportArgs) -> Result<u16, Box<dyn Error>> {
+    let characteristic = find_characteristic(
+        args,
+        peripheral,
+        args.notify_uuid,
+        "Sensor has no soil moisture characteristic",
+    )
+    .await?;
+    let value = if characteristic.properties.contains(CharPropFlags::NOTIFY) {
+        // Listen before subscribing, the sensor samples as soon as notifications are enabled.
+        let mut notifications = peripheral.notifications().await?;
+        timeout(args.ble_timeout(), peripheral.subscribe(&characteristic)).await??;
+        // Saves waiting for the next sample interval, an unchanged reading is notified too.
+        if let Some(read_now) = peripheral
+            .characteristics()
+            .into_iter()
+            .find(|characteristic| characteristic.uuid == READ_NOW_CHARACTERISTIC_UUID)
+        {
+            timeout(
+                args.ble_timeout(),
+                peripheral.write(&read_now, &[1], WriteType::WithResponse),
+            )
+            .await??;
+        }
+        loop {
+            let data = timeout(args.ble_timeout(), notifications.next())
+                .await?
+                .ok_or("Notification stream closed")?;
+            if data.uuid == args.notify_uuid {
+                break data.value;
+            }
+        }
+    } else {
+        timeout(args.ble_timeout(), peripheral.read(&characteristic)).await??
+    };
+    decode_moisture(&value, args.moisture_endian)
+        .ok_or_else(|| format!("Reading too short to decode: {:02x?}", value).into())
+}
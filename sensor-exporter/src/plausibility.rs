@@ -0,0 +1,136 @@
+//! Flags decoded readings that are out of the physically possible range,
+//! e.g. a stuck ADC notifying its full-scale value.
+
+use crate::sink::Reading;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Inclusive range of plausible values of one metric, written as `metric=min:max`.
+#[derive(Clone, Debug)]
+pub struct PlausibleRange {
+    metric: String,
+    min: f64,
+    max: f64,
+}
+
+impl FromStr for PlausibleRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid plausible range {:?}, expected metric=min:max", s);
+        let (metric, range) = s.split_once('=').ok_or_else(invalid)?;
+        let (min, max) = range.split_once(':').ok_or_else(invalid)?;
+        let min: f64 = min.trim().parse().map_err(|_| invalid())?;
+        let max: f64 = max.trim().parse().map_err(|_| invalid())?;
+        if metric.is_empty() || min > max {
+            return Err(invalid());
+        }
+        Ok(PlausibleRange {
+            metric: metric.trim().to_string(),
+            min,
+            max,
+        })
+    }
+}
+
+impl fmt::Display for PlausibleRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}:{}", self.metric, self.min, self.max)
+    }
+}
+
+/// Checks readings against their metric's plausible range.
+pub struct PlausibilityCheck {
+    ranges: Vec<PlausibleRange>,
+    drop_implausible: bool,
+    implausible_readings: IntCounterVec,
+}
+
+impl PlausibilityCheck {
+    pub fn new(ranges: Vec<PlausibleRange>, drop_implausible: bool) -> Self {
+        PlausibilityCheck {
+            ranges,
+            drop_implausible,
+            implausible_readings: register_int_counter_vec!(
+                "sensor_implausible_readings_total",
+                "Readings outside of their metric's plausible range",
+                &["device"]
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Counts and logs an implausible reading, returning whether it should still be published.
+    pub fn check(&self, reading: &Reading) -> bool {
+        let implausible = self.ranges.iter().any(|range| {
+            range.metric == reading.metric && !(range.min..=range.max).contains(&reading.value)
+        });
+        if !implausible {
+            return true;
+        }
+
+        warn!(
+            device = %reading.device,
+            metric = reading.metric,
+            value = reading.value,
+            "Implausible reading"
+        );
+        self.implausible_readings
+            .with_label_values(&[&reading.device])
+            .inc();
+        !self.drop_implausible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn reading(metric: &'static str, value: f64) -> Reading {
+        Reading {
+            device: "sensor".to_string(),
+            metric,
+            value,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range: PlausibleRange = "soil_moisture=0:4095".parse().unwrap();
+        assert_eq!(range.metric, "soil_moisture");
+        assert_eq!((range.min, range.max), (0.0, 4095.0));
+        assert_eq!(range.to_string(), "soil_moisture=0:4095");
+        let range: PlausibleRange = "temperature=-40:85.5".parse().unwrap();
+        assert_eq!((range.min, range.max), (-40.0, 85.5));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for range in ["soil_moisture", "soil_moisture=0", "=0:1", "soil_moisture=a:1", "soil_moisture=10:0"] {
+            assert!(range.parse::<PlausibleRange>().is_err(), "{}", range);
+        }
+    }
+
+    // A single check, as it registers its counter in the global registry.
+    #[test]
+    fn checks_readings_against_their_metric_range() {
+        let ranges = vec!["soil_moisture=100:4000".parse().unwrap()];
+        let mut check = PlausibilityCheck::new(ranges, false);
+        assert!(check.check(&reading("soil_moisture", 100.0)));
+        assert!(check.check(&reading("soil_moisture", 4000.0)));
+        assert!(check.check(&reading("sensor_rssi_dbm", -120.0)));
+        // Implausible readings are counted but still published, unless dropped.
+        assert!(check.check(&reading("soil_moisture", 4095.0)));
+        assert_eq!(
+            check.implausible_readings.with_label_values(&["sensor"]).get(),
+            1
+        );
+        check.drop_implausible = true;
+        assert!(!check.check(&reading("soil_moisture", 0.0)));
+        assert!(check.check(&reading("soil_moisture", 2000.0)));
+    }
+}
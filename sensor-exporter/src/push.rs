@@ -0,0 +1,156 @@
+//! Pushes the registered metrics to a Prometheus Pushgateway, for exporters
+//! Prometheus can't scrape, e.g. behind NAT.
+//!
+//! Every device's series are pushed to their own group, keyed by `job` and
+//! `device`, so a sensor going away doesn't wipe the others. Series without a
+//! device, like the exporter's own metrics, go to the group keyed by `job` only.
+
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time;
+use tracing::warn;
+
+/// Value of the `job` grouping label.
+const JOB: &str = "soil_moisture";
+
+/// Pushes every `interval` to the gateway at `url`, e.g. `http://pushgateway:9091`.
+pub async fn run(url: String, interval: Duration) {
+    let mut push = time::interval(interval);
+    loop {
+        push.tick().await;
+        for (device, families) in by_device(prometheus::gather()) {
+            let group_url = group_url(&url, device.as_deref());
+            if let Err(err) = tokio::task::block_in_place(|| put(&group_url, &families)) {
+                warn!(url = %group_url, %err, "Can't push metrics");
+            }
+        }
+    }
+}
+
+/// Splits metric families into the series of every device, `None` for series without one.
+fn by_device(families: Vec<MetricFamily>) -> HashMap<Option<String>, Vec<MetricFamily>> {
+    let mut groups: HashMap<Option<String>, Vec<MetricFamily>> = HashMap::new();
+    for family in families {
+        let mut metrics_by_device: HashMap<Option<String>, Vec<_>> = HashMap::new();
+        for metric in family.get_metric() {
+            let device = metric
+                .get_label()
+                .iter()
+                .find(|label| label.get_name() == "device")
+                .map(|label| label.get_value().to_string());
+            metrics_by_device
+                .entry(device)
+                .or_default()
+                .push(metric.clone());
+        }
+        for (device, metrics) in metrics_by_device {
+            let mut group_family = family.clone();
+            group_family.set_metric(metrics.into());
+            groups.entry(device).or_default().push(group_family);
+        }
+    }
+    groups
+}
+
+/// URL of a grouping key, the device base64 encoded as it may contain `/`.
+fn group_url(url: &str, device: Option<&str>) -> String {
+    let url = format!("{}/metrics/job/{}", url.trim_end_matches('/'), JOB);
+    match device {
+        Some("") => format!("{}/device@base64/=", url),
+        Some(device) => format!("{}/device@base64/{}", url, base64_url(device.as_bytes())),
+        None => url,
+    }
+}
+
+/// Replaces the metrics of a group with `families`.
+fn put(url: &str, families: &[MetricFamily]) -> Result<(), Box<dyn std::error::Error>> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(families, &mut body)?;
+    ureq::put(url)
+        .set("Content-Type", encoder.format_type())
+        .send_bytes(&body)?;
+    Ok(())
+}
+
+/// Unpadded base64 with the URL-safe alphabet, as the Pushgateway accepts in paths.
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | ((*byte as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{IntGauge, IntGaugeVec, Opts, Registry};
+
+    #[test]
+    fn groups_series_by_device() {
+        let registry = Registry::new();
+        let moisture = IntGaugeVec::new(Opts::new("soil_moisture", "help"), &["device"]).unwrap();
+        let up = IntGauge::new("exporter_up", "help").unwrap();
+        registry.register(Box::new(moisture.clone())).unwrap();
+        registry.register(Box::new(up.clone())).unwrap();
+        moisture.with_label_values(&["a"]).set(1000);
+        moisture.with_label_values(&["b"]).set(2000);
+        up.set(1);
+
+        let groups = by_device(registry.gather());
+        assert_eq!(groups.len(), 3);
+        for (device, value) in [("a", 1000.0), ("b", 2000.0)] {
+            let families = &groups[&Some(device.to_string())];
+            assert_eq!(families.len(), 1);
+            assert_eq!(families[0].get_name(), "soil_moisture");
+            assert_eq!(families[0].get_metric().len(), 1);
+            assert_eq!(families[0].get_metric()[0].get_gauge().get_value(), value);
+        }
+        let families = &groups[&None];
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "exporter_up");
+    }
+
+    #[test]
+    fn group_urls() {
+        assert_eq!(
+            group_url("http://pushgateway:9091/", None),
+            "http://pushgateway:9091/metrics/job/soil_moisture"
+        );
+        assert_eq!(
+            group_url("http://pushgateway:9091", Some("a/b")),
+            "http://pushgateway:9091/metrics/job/soil_moisture/device@base64/YS9i"
+        );
+        assert_eq!(
+            group_url("http://pushgateway:9091", Some("")),
+            "http://pushgateway:9091/metrics/job/soil_moisture/device@base64/="
+        );
+    }
+
+    #[test]
+    fn encodes_unpadded_url_safe_base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg"),
+            (b"fo", "Zm8"),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg"),
+            (b"fooba", "Zm9vYmE"),
+            (b"foobar", "Zm9vYmFy"),
+            (&[0xfb, 0xff], "-_8"),
+        ] {
+            assert_eq!(base64_url(bytes), encoded);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Order in which sensors get serviced when there are more of them than the
+//! adapter can keep connected at once.
+//!
+//! Every scan cycle sensors are visited from the most overdue to the least:
+//! a sensor never serviced before comes first, the others by the time since
+//! they were last serviced multiplied by their priority. A sensor with
+//! priority 2 thus gets serviced about twice as often as one with priority 1,
+//! and no sensor is starved since its score keeps growing while it waits.
+//!
+//! Sensors are told apart by their `device` label, their advertised name or
+//! their address with `--device-label address`, and so are their priorities.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Priority of sensors whose name has none configured.
+const DEFAULT_PRIORITY: u32 = 1;
+
+/// Priority of one sensor, written as `device=priority` with its `device` label.
+#[derive(Clone, Debug)]
+pub struct DevicePriority {
+    device: String,
+    priority: u32,
+}
+
+impl FromStr for DevicePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid priority {:?}, expected device=priority with priority > 0", s);
+        let (device, priority) = s.split_once('=').ok_or_else(invalid)?;
+        let priority: u32 = priority.trim().parse().map_err(|_| invalid())?;
+        if device.is_empty() || priority == 0 {
+            return Err(invalid());
+        }
+        Ok(DevicePriority {
+            device: device.trim().to_string(),
+            priority,
+        })
+    }
+}
+
+impl fmt::Display for DevicePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.device, self.priority)
+    }
+}
+
+/// Tracks when every sensor was last serviced, keyed by its `device` label.
+pub struct Scheduler {
+    priorities: HashMap<String, u32>,
+    last_serviced: HashMap<String, Instant>,
+}
+
+impl Scheduler {
+    pub fn new(priorities: &[DevicePriority]) -> Self {
+        Scheduler {
+            priorities: priorities
+                .iter()
+                .map(|priority| (priority.device.clone(), priority.priority))
+                .collect(),
+            last_serviced: HashMap::new(),
+        }
+    }
+
+    /// Sorts `devices` by their `device` labels from the most overdue to the least.
+    pub fn order<T>(&self, devices: &mut [(String, T)]) {
+        let now = Instant::now();
+        devices.sort_by_cached_key(|(name, _)| {
+            let priority = self.priorities.get(name).copied().unwrap_or(DEFAULT_PRIORITY);
+            let overdue = match self.last_serviced.get(name) {
+                Some(last) => now.duration_since(*last).as_millis(),
+                None => u128::MAX / u128::from(u32::MAX),
+            };
+            std::cmp::Reverse(overdue * u128::from(priority))
+        });
+    }
+
+    /// Records that `device` was just serviced.
+    pub fn serviced(&mut self, device: &str) {
+        self.last_serviced.insert(device.to_string(), Instant::now());
+    }
+}
+
+/// How long to wait for the next notification, at most `max` and never past `deadline`.
+pub fn next_wait(deadline: Option<Instant>, max: Duration) -> Duration {
+    match deadline {
+        Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(max),
+        None => max,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(devices: &[(String, ())]) -> Vec<&str> {
+        devices.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn serviced_ago(scheduler: &mut Scheduler, device: &str, ago: Duration) {
+        let last = Instant::now().checked_sub(ago).unwrap();
+        scheduler.last_serviced.insert(device.to_string(), last);
+    }
+
+    #[test]
+    fn parses_priorities() {
+        let priority: DevicePriority = "greenhouse=3".parse().unwrap();
+        assert_eq!((priority.device.as_str(), priority.priority), ("greenhouse", 3));
+        assert_eq!(priority.to_string(), "greenhouse=3");
+        for priority in ["greenhouse", "=3", "greenhouse=0", "greenhouse=high"] {
+            assert!(priority.parse::<DevicePriority>().is_err(), "{}", priority);
+        }
+    }
+
+    #[test]
+    fn most_overdue_first_and_never_serviced_before_all() {
+        let mut scheduler = Scheduler::new(&[]);
+        serviced_ago(&mut scheduler, "a", Duration::from_secs(10));
+        serviced_ago(&mut scheduler, "b", Duration::from_secs(60));
+        let mut devices = vec![("a".to_string(), ()), ("b".to_string(), ()), ("new".to_string(), ())];
+        scheduler.order(&mut devices);
+        assert_eq!(names(&devices), ["new", "b", "a"]);
+    }
+
+    #[test]
+    fn priority_scales_overdue_time() {
+        let priorities = ["high=4".parse().unwrap()];
+        let mut scheduler = Scheduler::new(&priorities);
+        serviced_ago(&mut scheduler, "high", Duration::from_secs(20));
+        serviced_ago(&mut scheduler, "low", Duration::from_secs(60));
+        let mut devices = vec![("low".to_string(), ()), ("high".to_string(), ())];
+        scheduler.order(&mut devices);
+        assert_eq!(names(&devices), ["high", "low"]);
+
+        // Serviced just now, even a high priority sensor waits its turn.
+        scheduler.serviced("high");
+        scheduler.order(&mut devices);
+        assert_eq!(names(&devices), ["low", "high"]);
+    }
+}
@@ -0,0 +1,125 @@
+//! HTTP server exposing the metrics, readings and health endpoints.
+//!
+//! Every listener of the exporter must be created with `bind`, so IPv6 and
+//! dual-stack addresses behave the same everywhere.
+
+use crate::extremes::Extremes;
+use crate::health::Health;
+use crate::snapshot;
+use prometheus::{Encoder, TextEncoder};
+use socket2::{Domain, Socket, Type};
+use std::error::Error;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Response};
+
+/// Parses a listen address like `127.0.0.1:3737` or `[::]:3737`.
+pub fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "invalid address {:?}, expected IP:PORT with IPv6 addresses in brackets, e.g. 127.0.0.1:3737 or [::]:3737",
+            s
+        )
+    })
+}
+
+/// Binds a TCP listener on `addr`.
+///
+/// IPv6 listeners accept IPv4 connections too (as IPv4-mapped addresses) only
+/// with `dual_stack`, regardless of the platform's default.
+pub fn bind(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    } else if dual_stack {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("dual-stack needs an IPv6 address like [::]:{}", addr.port()),
+        ));
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Serves the endpoints on `listener` from a background thread.
+pub fn start(
+    listener: TcpListener,
+    health: Arc<Health>,
+    extremes: Arc<Extremes>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = tiny_http::Server::from_listener(listener, None)?;
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (_, "/metrics") => metrics(),
+                (_, "/healthz") => healthz(&health),
+                (_, "/readings") => readings(),
+                (Method::Post, "/reset-extremes") => {
+                    extremes.reset();
+                    Response::from_string("OK")
+                }
+                _ => Response::from_string("Not found").with_status_code(404),
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+fn metrics() -> Response<io::Cursor<Vec<u8>>> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return Response::from_string(err.to_string()).with_status_code(500);
+    }
+    Response::from_data(buffer)
+        .with_header(Header::from_bytes("Content-Type", encoder.format_type()).unwrap())
+}
+
+/// Every sensor's current reading, RSSI, connection and last seen time as a JSON object by device.
+fn readings() -> Response<io::Cursor<Vec<u8>>> {
+    match snapshot::to_json(&prometheus::gather()) {
+        Ok(json) => Response::from_string(json)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+        Err(err) => Response::from_string(err.to_string()).with_status_code(500),
+    }
+}
+
+/// 200 while the exporter is healthy, 503 with the reason otherwise.
+fn healthz(health: &Health) -> Response<io::Cursor<Vec<u8>>> {
+    match health.problem() {
+        None => Response::from_string("OK"),
+        Some(problem) => Response::from_string(problem).with_status_code(503),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ipv4_and_ipv6_addresses() {
+        assert_eq!(parse_addr("127.0.0.1:3737"), Ok(SocketAddr::from(([127, 0, 0, 1], 3737))));
+        let addr = parse_addr("[::]:3737").unwrap();
+        assert!(addr.is_ipv6());
+        assert_eq!(addr.port(), 3737);
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for addr in ["127.0.0.1", "::1:3737", "localhost:3737", "127.0.0.1:http", ""] {
+            assert!(parse_addr(addr).is_err(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn dual_stack_needs_ipv6() {
+        let err = bind(([127, 0, 0, 1], 0).into(), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bind(([127, 0, 0, 1], 0).into(), false).is_ok());
+    }
+}
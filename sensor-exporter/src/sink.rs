@@ -0,0 +1,277 @@
+//! Output sinks receiving every decoded sensor reading.
+
+use crate::influx::InfluxSink;
+use crate::timestamped::TimestampedGauge;
+use crate::ExportArgs;
+use clap::ValueEnum;
+use prometheus::{register_gauge_vec, GaugeVec};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// A single decoded value notified by a sensor.
+#[derive(Clone, Debug)]
+pub struct Reading {
+    /// Local name of the sensor.
+    pub device: String,
+    /// Name of the measured quantity, e.g. `soil_moisture`.
+    pub metric: &'static str,
+    pub value: f64,
+    /// When the notification carrying the value was received.
+    pub timestamp: SystemTime,
+}
+
+/// A destination for readings, e.g. a metrics endpoint or a time-series database.
+pub trait Sink: Send {
+    /// Hands a reading over to the sink, which may buffer it until `flush`.
+    fn publish(&mut self, reading: &Reading);
+
+    /// Delivers buffered readings, called periodically.
+    fn flush(&mut self) {}
+}
+
+/// Sinks that can be enabled on the command line.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinkKind {
+    /// Gauges served on the `/metrics` endpoint.
+    Prometheus,
+    /// Line protocol points written to `--influx-write-url`.
+    Influx,
+}
+
+impl SinkKind {
+    pub fn build(self, args: &ExportArgs) -> Box<dyn Sink> {
+        let stale_after =
+            (args.moisture_stale_secs > 0).then(|| Duration::from_secs(args.moisture_stale_secs));
+        match self {
+            SinkKind::Prometheus => {
+                Box::new(PrometheusSink::new(args.export_timestamps, stale_after))
+            }
+            SinkKind::Influx => {
+                Box::new(InfluxSink::new(args.influx_write_url.clone().unwrap_or_default()))
+            }
+        }
+    }
+}
+
+/// Help text of the gauge exposing a metric.
+fn metric_help(metric: &str) -> &'static str {
+    match metric {
+        "soil_moisture" => "Soil moisture reading as notified by the sensor",
+        "soil_moisture_smoothed" => "Exponentially weighted moving average of the soil moisture",
+        "soil_moisture_percent" => "Soil moisture in percent between the calibration points",
+        _ => "Sensor reading",
+    }
+}
+
+/// Exposes readings as gauges on the `/metrics` endpoint, a gauge per metric
+/// labeled by `device`.
+///
+/// Gauges are registered on the first reading of their metric and a device's
+/// series is created on its first reading, so nothing reads 0 before a sensor
+/// actually notified a value. Series not updated for `stale_after` read NaN
+/// instead of holding a dead sensor's last value, until its next reading.
+pub struct PrometheusSink {
+    /// Gauge of every metric, `None` when it couldn't be registered.
+    gauges: HashMap<&'static str, Option<GaugeVec>>,
+    /// Used instead of `gauges` when exporting the time readings were received.
+    timestamped_gauges: Option<HashMap<&'static str, Option<TimestampedGauge>>>,
+    stale_after: Option<Duration>,
+    /// When every series not marked stale yet was last set.
+    last_set: HashMap<(String, &'static str), Instant>,
+}
+
+impl PrometheusSink {
+    fn new(export_timestamps: bool, stale_after: Option<Duration>) -> Self {
+        PrometheusSink {
+            gauges: HashMap::new(),
+            timestamped_gauges: export_timestamps.then(HashMap::new),
+            stale_after,
+            last_set: HashMap::new(),
+        }
+    }
+}
+
+impl PrometheusSink {
+    fn set(&mut self, reading: &Reading) {
+        if let Some(timestamped_gauges) = self.timestamped_gauges.as_mut() {
+            let gauge = timestamped_gauges.entry(reading.metric).or_insert_with(|| {
+                registered(
+                    reading.metric,
+                    TimestampedGauge::register(reading.metric, metric_help(reading.metric)),
+                )
+            });
+            if let Some(gauge) = gauge {
+                gauge.set(&reading.device, reading.value, reading.timestamp);
+            }
+        } else {
+            let gauge = self.gauges.entry(reading.metric).or_insert_with(|| {
+                registered(
+                    reading.metric,
+                    register_gauge_vec!(reading.metric, metric_help(reading.metric), &["device"]),
+                )
+            });
+            if let Some(gauge) = gauge {
+                gauge.with_label_values(&[&reading.device]).set(reading.value);
+            }
+        }
+    }
+}
+
+/// The newly registered gauge of `metric`, `None` with the reason logged when
+/// registering it failed, e.g. as another metric took its name.
+fn registered<T>(metric: &str, gauge: prometheus::Result<T>) -> Option<T> {
+    match gauge {
+        Ok(gauge) => Some(gauge),
+        Err(err) => {
+            error!(metric, %err, "Can't register the gauge, dropping its readings");
+            None
+        }
+    }
+}
+
+impl Sink for PrometheusSink {
+    fn publish(&mut self, reading: &Reading) {
+        self.set(reading);
+        if self.stale_after.is_some() {
+            self.last_set
+                .insert((reading.device.clone(), reading.metric), Instant::now());
+        }
+    }
+
+    fn flush(&mut self) {
+        let Some(stale_after) = self.stale_after else {
+            return;
+        };
+        let stale: Vec<_> = self
+            .last_set
+            .iter()
+            .filter(|(_, last_set)| last_set.elapsed() >= stale_after)
+            .map(|(series, _)| series.clone())
+            .collect();
+        for (device, metric) in stale {
+            info!(%device, metric, ?stale_after, "No recent reading, marking it stale");
+            self.last_set.remove(&(device.clone(), metric));
+            self.set(&Reading {
+                device,
+                metric,
+                value: f64::NAN,
+                timestamp: SystemTime::now(),
+            });
+        }
+    }
+}
+
+/// Readings a sink may fall behind by, newer ones are dropped for it meanwhile.
+const SINK_QUEUE_LEN: usize = 64;
+
+/// Fans every received reading out to all sinks, flushing them every `flush_interval`.
+///
+/// Every sink runs in a task of its own with its own queue, so a slow one, e.g.
+/// writing to an unreachable database, holds up neither the other sinks nor
+/// the sessions sending readings.
+pub async fn run(
+    sinks: Vec<Box<dyn Sink>>,
+    mut readings: mpsc::Receiver<Reading>,
+    flush_interval: Duration,
+) {
+    let queues: Vec<_> = sinks
+        .into_iter()
+        .map(|sink| {
+            let (queue, queued) = mpsc::channel(SINK_QUEUE_LEN);
+            (queue, tokio::spawn(run_sink(sink, queued, flush_interval)))
+        })
+        .collect();
+    while let Some(reading) = readings.recv().await {
+        for (queue, _) in &queues {
+            if let Err(mpsc::error::TrySendError::Full(reading)) = queue.try_send(reading.clone()) {
+                warn!(device = %reading.device, metric = reading.metric, "A sink is falling behind, dropping a reading");
+            }
+        }
+    }
+    // Closed queues have every sink flush a last time.
+    for (queue, sink) in queues {
+        drop(queue);
+        let _ = sink.await;
+    }
+}
+
+/// Publishes the queued readings to `sink`, flushing it every `flush_interval`.
+async fn run_sink(
+    mut sink: Box<dyn Sink>,
+    mut readings: mpsc::Receiver<Reading>,
+    flush_interval: Duration,
+) {
+    let mut flush = time::interval(flush_interval);
+    loop {
+        tokio::select! {
+            reading = readings.recv() => match reading {
+                Some(reading) => sink.publish(&reading),
+                None => break,
+            },
+            _ = flush.tick() => sink.flush(),
+        }
+    }
+    sink.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn sink(stale_after: Option<Duration>) -> PrometheusSink {
+        PrometheusSink::new(false, stale_after)
+    }
+
+    fn reading(metric: &'static str, value: f64) -> Reading {
+        Reading {
+            device: "sensor".to_string(),
+            metric,
+            value,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    fn value(sink: &PrometheusSink, metric: &str) -> f64 {
+        let gauge = sink.gauges[metric].as_ref().unwrap();
+        gauge.with_label_values(&["sensor"]).get()
+    }
+
+    #[test]
+    fn marks_stale_series_nan_until_the_next_reading() {
+        let mut sink = sink(Some(Duration::from_millis(50)));
+        sink.publish(&reading("test_stale_reading", 1000.0));
+        sink.flush();
+        assert_eq!(value(&sink, "test_stale_reading"), 1000.0);
+
+        thread::sleep(Duration::from_millis(60));
+        sink.flush();
+        assert!(value(&sink, "test_stale_reading").is_nan());
+        assert!(sink.last_set.is_empty());
+
+        sink.publish(&reading("test_stale_reading", 1010.0));
+        assert_eq!(value(&sink, "test_stale_reading"), 1010.0);
+        assert_eq!(sink.last_set.len(), 1);
+    }
+
+    #[test]
+    fn keeps_the_last_value_without_a_window() {
+        let mut sink = sink(None);
+        sink.publish(&reading("test_kept_reading", 1000.0));
+        thread::sleep(Duration::from_millis(10));
+        sink.flush();
+        assert_eq!(value(&sink, "test_kept_reading"), 1000.0);
+        assert!(sink.last_set.is_empty());
+    }
+
+    #[test]
+    fn drops_readings_of_a_metric_whose_name_is_taken() {
+        prometheus::register_gauge!("test_taken_reading", "help").unwrap();
+        let mut sink = sink(None);
+        sink.publish(&reading("test_taken_reading", 1000.0));
+        assert!(sink.gauges["test_taken_reading"].is_none());
+    }
+}
@@ -0,0 +1,52 @@
+//! Exponentially weighted moving average of readings, applied at the gateway.
+//!
+//! Smoothing here rather than with PromQL (e.g. `avg_over_time`) keeps dashboards
+//! and alerts simple and works for every sink, but the raw samples are gone from
+//! the smoothed series: the window can't be changed after the fact and a real
+//! step change shows up with a lag of roughly `1 / alpha` readings. The raw value
+//! stays exported next to the smoothed one for that reason.
+
+/// Parses a smoothing factor, which must be within `[0, 1]`. 0 disables smoothing
+/// and 1 exports the raw value as the smoothed one.
+pub fn parse_alpha(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(alpha) if (0.0..=1.0).contains(&alpha) => Ok(alpha),
+        _ => Err(format!("invalid smoothing factor {:?}, expected a number within [0, 1]", s)),
+    }
+}
+
+/// EWMA state of one device, start a new one on every (re)connect.
+pub struct Ewma {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(alpha: f64) -> Self {
+        Ewma { alpha, value: None }
+    }
+
+    /// Feeds a new sample in, returning the smoothed value. The first sample is taken as is.
+    pub fn update(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(value) => self.alpha * sample + (1.0 - self.alpha) * value,
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_factors_from_zero_to_one() {
+        assert_eq!(parse_alpha("0"), Ok(0.0));
+        assert_eq!(parse_alpha("0.3"), Ok(0.3));
+        assert_eq!(parse_alpha("1"), Ok(1.0));
+        assert!(parse_alpha("1.5").is_err());
+        assert!(parse_alpha("-0.1").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! Current state of every sensor as JSON for the `/readings` endpoint, for
+//! scripts polling the exporter without a Prometheus stack.
+//!
+//! The snapshot is taken from the gathered metrics rather than kept on the
+//! side, so it can't disagree with what `/metrics` exposes.
+
+use prometheus::proto::{Metric, MetricFamily};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// What's known about a sensor, `None` (`null` in JSON) until it's known. A
+/// stale reading is `null` too, as its gauge reads NaN.
+#[derive(Default, Serialize)]
+struct SensorSnapshot {
+    raw: Option<f64>,
+    percent: Option<f64>,
+    rssi: Option<f64>,
+    connected: bool,
+    /// Unix time the last reading was decoded.
+    last_seen: Option<f64>,
+}
+
+/// JSON object of every sensor's snapshot, by `device`.
+pub fn to_json(families: &[MetricFamily]) -> serde_json::Result<String> {
+    let mut sensors: BTreeMap<String, SensorSnapshot> = BTreeMap::new();
+    for family in families {
+        let field: fn(&mut SensorSnapshot, f64) = match family.get_name() {
+            "soil_moisture" => |sensor, value| sensor.raw = Some(value),
+            "soil_moisture_percent" => |sensor, value| sensor.percent = Some(value),
+            "sensor_rssi_dbm" => |sensor, value| sensor.rssi = Some(value),
+            "sensor_connected" => |sensor, value| sensor.connected = value == 1.0,
+            "sensor_last_update_timestamp_seconds" => {
+                |sensor, value| sensor.last_seen = Some(value)
+            }
+            _ => continue,
+        };
+        for metric in family.get_metric() {
+            if let Some(device) = device(metric) {
+                let sensor = sensors.entry(device.to_string()).or_default();
+                field(sensor, metric.get_gauge().get_value());
+            }
+        }
+    }
+    serde_json::to_string(&sensors)
+}
+
+/// Value of the `device` label of a series.
+fn device(metric: &Metric) -> Option<&str> {
+    metric
+        .get_label()
+        .iter()
+        .find(|label| label.get_name() == "device")
+        .map(|label| label.get_value())
+}
@@ -0,0 +1,80 @@
+//! State of the exporter kept across restarts in a small JSON file.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct State {
+    /// Number of times the exporter was started.
+    pub restarts: u64,
+}
+
+/// Where the state is kept unless told otherwise: `sensor-exporter/state.json`
+/// in `$XDG_STATE_HOME` or `~/.local/state`, or in the working directory when
+/// neither is set.
+pub fn default_path() -> PathBuf {
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_default();
+    dir.join("sensor-exporter").join("state.json")
+}
+
+impl State {
+    /// Loads the state from `path`, a missing file being an empty state.
+    pub fn load(path: &Path) -> Result<State, Box<dyn Error>> {
+        match fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves the state to `path`, replacing the file atomically so a crash can't truncate it.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sensor-exporter-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_state() {
+        let state = State::load(&state_file("missing")).unwrap();
+        assert_eq!(state.restarts, 0);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = state_file("saved");
+        State { restarts: 3 }.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        assert_eq!(State::load(&path).unwrap().restarts, 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn broken_file_is_an_error() {
+        let path = state_file("broken");
+        fs::write(&path, "{\"restarts\":").unwrap();
+        assert!(State::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
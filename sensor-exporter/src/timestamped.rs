@@ -0,0 +1,107 @@
+//! Gauges exposing the time their value was received as the sample timestamp.
+//!
+//! Prometheus otherwise stamps samples with the scrape time, which is up to a
+//! scrape interval off for sensors notifying rarely.
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A gauge labeled by `device` whose last value per device is exposed with an
+/// explicit timestamp. A device's series only appears once it was set.
+#[derive(Clone)]
+pub struct TimestampedGauge {
+    desc: Desc,
+    /// Last value of every device and its timestamp in milliseconds since the Unix epoch.
+    samples: Arc<Mutex<HashMap<String, (f64, i64)>>>,
+}
+
+impl TimestampedGauge {
+    /// Creates the gauge and registers it in the default registry.
+    pub fn register(name: &str, help: &str) -> prometheus::Result<Self> {
+        let gauge = TimestampedGauge {
+            desc: Desc::new(
+                name.to_string(),
+                help.to_string(),
+                vec!["device".to_string()],
+                HashMap::new(),
+            )?,
+            samples: Arc::new(Mutex::new(HashMap::new())),
+        };
+        prometheus::register(Box::new(gauge.clone()))?;
+        Ok(gauge)
+    }
+
+    pub fn set(&self, device: &str, value: f64, timestamp: SystemTime) {
+        let timestamp_ms = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as i64);
+        self.samples
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), (value, timestamp_ms));
+    }
+}
+
+impl Collector for TimestampedGauge {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let samples = self.samples.lock().unwrap();
+        if samples.is_empty() {
+            return vec![];
+        }
+
+        let metrics: Vec<proto::Metric> = samples
+            .iter()
+            .map(|(device, (value, timestamp_ms))| {
+                let mut label = proto::LabelPair::default();
+                label.set_name("device".to_string());
+                label.set_value(device.clone());
+                let mut gauge = proto::Gauge::default();
+                gauge.set_value(*value);
+                let mut metric = proto::Metric::default();
+                metric.set_label(vec![label].into());
+                metric.set_gauge(gauge);
+                metric.set_timestamp_ms(*timestamp_ms);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn exposes_the_last_value_with_its_timestamp() {
+        let gauge = TimestampedGauge::register("test_timestamped_gauge", "help").unwrap();
+        assert!(gauge.collect().is_empty());
+
+        gauge.set("sensor", 1.0, UNIX_EPOCH + Duration::from_millis(1000));
+        gauge.set("sensor", 1.5, UNIX_EPOCH + Duration::from_millis(1234));
+        let families = gauge.collect();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "test_timestamped_gauge");
+        assert_eq!(families[0].get_field_type(), MetricType::GAUGE);
+        let metrics = families[0].get_metric();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].get_gauge().get_value(), 1.5);
+        assert_eq!(metrics[0].get_timestamp_ms(), 1234);
+        let label = &metrics[0].get_label()[0];
+        assert_eq!((label.get_name(), label.get_value()), ("device", "sensor"));
+    }
+}
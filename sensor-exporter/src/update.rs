@@ -0,0 +1,235 @@
+//! Firmware updates over the firmware's DFU characteristics.
+//!
+//! The image is uploaded page by page as `[offset: u32, data]` chunks, waiting
+//! for the sensor to write every page to flash before sending the next one.
+//! Once the upload is finished the sensor reboots into its bootloader, which
+//! swaps the new image in. The new image runs on trial: it's only confirmed
+//! after reconnecting and verifying it, otherwise the sensor rolls it back on
+//! its own.
+
+use crate::manage::{find_characteristic, find_peripheral, send_command};
+use crate::ExportArgs;
+use btleplug::api::{bleuuid::uuid_from_u16, Characteristic, Peripheral as _, WriteType};
+use btleplug::platform::{Adapter, Peripheral};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::time::{self, timeout};
+use uuid::Uuid;
+
+/// UUID of the characteristic taking `[offset: u32, data]` chunks of the image.
+const DFU_DATA_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xdf01);
+/// UUID of the characteristic holding the number of image bytes written to flash.
+const DFU_PROGRESS_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xdf02);
+/// UUID of the sensor status characteristic.
+const STATUS_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xbab1);
+/// UUID of the diagnostics characteristic holding the NUL padded firmware version.
+const FIRMWARE_VERSION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xd1a2);
+
+const CMD_CONFIRM_UPDATE: u8 = 0x30;
+const CMD_UPDATE_BEGIN: u8 = 0x31;
+const CMD_UPDATE_FINISH: u8 = 0x32;
+
+/// Set in the status byte while a freshly installed image waits for its confirmation.
+const FLAG_UPDATE_PENDING: u8 = 1 << 4;
+
+/// Pages are written to flash one at a time, chunks must not cross them.
+const PAGE_SIZE: usize = 4096;
+/// Most image bytes per chunk, fitting the firmware's 256 byte ATT MTU with the offset.
+const CHUNK_SIZE: usize = 240;
+/// How long the sensor may take to write a page.
+const PAGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the sensor may take to mark the new image as booted once confirmed.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to keep looking for the sensor after it rebooted into the new image.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Uploads `image` to the sensor named `device`, then reconnects and confirms it.
+pub async fn run(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    device: &str,
+    image: &Path,
+    expect_version: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let image = fs::read(image)
+        .map_err(|err| format!("Can't read firmware image {}: {}", image.display(), err))?;
+    if image.is_empty() {
+        return Err("Firmware image is empty".into());
+    }
+
+    let peripheral = connect(adapter_list, args, device).await?;
+    let result = upload(args, &peripheral, &image).await;
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await;
+    result?;
+
+    println!("Waiting for {:?} to boot the new image...", device);
+    let peripheral = reconnect(adapter_list, args, device)
+        .await
+        .map_err(|err| format!("{}, the sensor rolls the update back on its own", err))?;
+    let result = verify_and_confirm(args, &peripheral, expect_version).await;
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await;
+    result
+}
+
+async fn connect(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    device: &str,
+) -> Result<Peripheral, Box<dyn Error>> {
+    let peripheral = find_peripheral(adapter_list, args, device)
+        .await?
+        .ok_or_else(|| format!("Sensor {:?} was not found", device))?;
+    if !peripheral.is_connected().await? {
+        crate::connect(args, &peripheral).await.map_err(|err| err.to_string())?;
+    }
+    Ok(peripheral)
+}
+
+/// Keeps trying to connect until `RECONNECT_TIMEOUT` passed.
+async fn reconnect(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    device: &str,
+) -> Result<Peripheral, Box<dyn Error>> {
+    let deadline = Instant::now() + RECONNECT_TIMEOUT;
+    // Give the bootloader time to swap the images.
+    time::sleep(Duration::from_secs(10)).await;
+    loop {
+        match connect(adapter_list, args, device).await {
+            Ok(peripheral) => return Ok(peripheral),
+            Err(err) if Instant::now() >= deadline => {
+                return Err(format!("Can't reconnect to {:?}: {}", device, err).into())
+            }
+            Err(_) => time::sleep(Duration::from_secs(5)).await,
+        }
+    }
+}
+
+/// Uploads the image and finishes the upload, which reboots the sensor.
+async fn upload(args: &ExportArgs, peripheral: &Peripheral, image: &[u8]) -> Result<(), Box<dyn Error>> {
+    let missing = "Sensor firmware does not support updates (no DFU characteristics)";
+    let data = find_characteristic(args, peripheral, DFU_DATA_CHARACTERISTIC_UUID, missing).await?;
+    let progress =
+        find_characteristic(args, peripheral, DFU_PROGRESS_CHARACTERISTIC_UUID, missing).await?;
+
+    send_command(args, peripheral, "UpdateBegin", CMD_UPDATE_BEGIN).await?;
+    for (page_index, page) in image.chunks(PAGE_SIZE).enumerate() {
+        let page_offset = page_index * PAGE_SIZE;
+        for (chunk_index, chunk) in page.chunks(CHUNK_SIZE).enumerate() {
+            let offset = (page_offset + chunk_index * CHUNK_SIZE) as u32;
+            let mut value = offset.to_le_bytes().to_vec();
+            value.extend_from_slice(chunk);
+            timeout(
+                args.ble_timeout(),
+                peripheral.write(&data, &value, WriteType::WithResponse),
+            )
+            .await??;
+        }
+        // The last page is written when finishing the upload.
+        if page.len() == PAGE_SIZE {
+            let written = (page_offset + PAGE_SIZE) as u32;
+            wait_for_progress(args, peripheral, &progress, written).await?;
+        }
+        println!(
+            "Uploaded {} of {} bytes",
+            page_offset + page.len(),
+            image.len()
+        );
+    }
+    send_command(args, peripheral, "UpdateFinish", CMD_UPDATE_FINISH).await
+}
+
+/// Polls the progress characteristic until the sensor wrote `written` bytes to flash.
+async fn wait_for_progress(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    progress: &Characteristic,
+    written: u32,
+) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + PAGE_TIMEOUT;
+    loop {
+        let value = timeout(args.ble_timeout(), peripheral.read(progress)).await??;
+        let value = <[u8; 4]>::try_from(value)
+            .map(u32::from_le_bytes)
+            .map_err(|value| format!("Unexpected DFU progress {:02x?}", value))?;
+        if value >= written {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "Sensor stopped writing the image at {} of {} bytes",
+                value, written
+            )
+            .into());
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Checks that the sensor runs a new image on trial, with the expected version, and confirms it.
+async fn verify_and_confirm(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    expect_version: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let status = find_characteristic(
+        args,
+        peripheral,
+        STATUS_CHARACTERISTIC_UUID,
+        "Sensor has no status characteristic",
+    )
+    .await?;
+    if read_flags(args, peripheral, &status).await? & FLAG_UPDATE_PENDING == 0 {
+        return Err("Sensor is not running a new image, the bootloader did not install it".into());
+    }
+
+    let version = find_characteristic(
+        args,
+        peripheral,
+        FIRMWARE_VERSION_CHARACTERISTIC_UUID,
+        "Sensor does not report its firmware version",
+    )
+    .await?;
+    let version = timeout(args.ble_timeout(), peripheral.read(&version)).await??;
+    let version = String::from_utf8_lossy(&version)
+        .trim_end_matches('\0')
+        .to_string();
+    println!("Sensor runs firmware version {}", version);
+    if let Some(expected) = expect_version {
+        if version != expected {
+            return Err(format!(
+                "Expected firmware version {}, not confirming so the sensor rolls back",
+                expected
+            )
+            .into());
+        }
+    }
+
+    // Fails unless the sensor reports the command's status, it would roll back otherwise.
+    send_command(args, peripheral, "ConfirmUpdate", CMD_CONFIRM_UPDATE).await?;
+    // The sensor marks the image as booted in the background, it's only kept once that's done.
+    let deadline = Instant::now() + CONFIRM_TIMEOUT;
+    while read_flags(args, peripheral, &status).await? & FLAG_UPDATE_PENDING != 0 {
+        if Instant::now() >= deadline {
+            return Err("Sensor did not keep the new image, it rolls the update back".into());
+        }
+        time::sleep(Duration::from_millis(500)).await;
+    }
+    println!("Update confirmed, the sensor keeps the new image.");
+    Ok(())
+}
+
+/// Reads the sensor's status byte.
+async fn read_flags(
+    args: &ExportArgs,
+    peripheral: &Peripheral,
+    status: &Characteristic,
+) -> Result<u8, Box<dyn Error>> {
+    let value = timeout(args.ble_timeout(), peripheral.read(status)).await??;
+    value
+        .first()
+        .copied()
+        .ok_or_else(|| "Sensor returned an empty status".into())
+}
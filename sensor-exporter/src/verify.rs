@@ -0,0 +1,190 @@
+//! One-shot end-to-end check of the setup: adapter, sensor, GATT layout and a first reading.
+
+use crate::{scan_filter, ExportArgs};
+use btleplug::api::{Central, CharPropFlags, Peripheral as _};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::StreamExt;
+use sensor_exporter::decode_moisture;
+use serde::Serialize;
+use std::time::Instant;
+use tokio::time::{self, timeout};
+
+/// Everything learned while verifying the setup.
+#[derive(Default, Serialize)]
+pub struct Report {
+    pub success: bool,
+    pub adapters: Vec<String>,
+    pub matched_devices: Vec<String>,
+    pub connect_millis: Option<u128>,
+    pub services: Vec<String>,
+    pub characteristics: Vec<String>,
+    pub first_reading: Option<u16>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    fn print(&self) {
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                String::from("none")
+            } else {
+                items.join(", ")
+            }
+        };
+        println!("Adapters:          {}", list(&self.adapters));
+        println!("Matched devices:   {}", list(&self.matched_devices));
+        if let Some(connect_millis) = self.connect_millis {
+            println!("Connect time:      {}ms", connect_millis);
+        }
+        println!("Services:          {}", list(&self.services));
+        println!("Characteristics:   {}", list(&self.characteristics));
+        match self.first_reading {
+            Some(reading) => println!("First reading:     {}", reading),
+            None => println!("First reading:     none"),
+        }
+        for warning in self.warnings.iter() {
+            println!("Warning:           {}", warning);
+        }
+        println!("Result:            {}", if self.success { "OK" } else { "FAILED" });
+    }
+}
+
+/// Runs the check on the first peripheral matching the exporter's options and
+/// prints the report, returning whether the setup works.
+pub async fn run(adapter_list: &[Adapter], args: &ExportArgs, json: bool) -> bool {
+    let mut report = Report::default();
+    if let Err(warning) = verify(adapter_list, args, &mut report).await {
+        report.warnings.push(warning);
+    }
+    report.success = report.first_reading.is_some();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        report.print();
+    }
+    report.success
+}
+
+/// Fills in the report, returning the problem that stopped the check early.
+async fn verify(
+    adapter_list: &[Adapter],
+    args: &ExportArgs,
+    report: &mut Report,
+) -> Result<(), String> {
+    if adapter_list.is_empty() {
+        return Err(String::from("No Bluetooth adapters found"));
+    }
+
+    let mut matched = Vec::new();
+    for adapter in adapter_list.iter() {
+        let info = adapter
+            .adapter_info()
+            .await
+            .unwrap_or_else(|_| String::from("(adapter info unknown)"));
+        report.adapters.push(info);
+
+        if let Err(err) = adapter.start_scan(scan_filter(args)).await {
+            report.warnings.push(format!("Scan failed: {}", err));
+            continue;
+        }
+        time::sleep(args.scan_duration()).await;
+
+        for peripheral in adapter.peripherals().await.unwrap_or_default() {
+            let local_name = match peripheral.properties().await {
+                Ok(Some(properties)) => properties.local_name,
+                _ => None,
+            };
+            if let Some(local_name) = local_name {
+                if args.matches(peripheral.address(), &local_name) {
+                    report.matched_devices.push(local_name);
+                    matched.push(peripheral);
+                }
+            }
+        }
+    }
+
+    let peripheral = matched
+        .first()
+        .ok_or_else(|| String::from("No matching peripheral found"))?;
+    if matched.len() > 1 {
+        report.warnings.push(String::from(
+            "Several matching peripherals found, only the first one was verified",
+        ));
+    }
+
+    let started = Instant::now();
+    timeout(args.ble_timeout(), peripheral.connect())
+        .await
+        .map_err(|_| String::from("Connect timed out"))?
+        .map_err(|err| format!("Connect failed: {}", err))?;
+    report.connect_millis = Some(started.elapsed().as_millis());
+
+    let result = verify_connected(peripheral, args, report).await;
+    let _ = timeout(args.ble_timeout(), peripheral.disconnect()).await;
+    result
+}
+
+async fn verify_connected(
+    peripheral: &Peripheral,
+    args: &ExportArgs,
+    report: &mut Report,
+) -> Result<(), String> {
+    let notify_uuid = args.notify_uuid;
+    timeout(args.ble_timeout(), peripheral.discover_services())
+        .await
+        .map_err(|_| String::from("Service discovery timed out"))?
+        .map_err(|err| format!("Service discovery failed: {}", err))?;
+    report.services = peripheral
+        .services()
+        .iter()
+        .map(|service| service.uuid.to_string())
+        .collect();
+    report.characteristics = peripheral
+        .characteristics()
+        .iter()
+        .map(|characteristic| characteristic.uuid.to_string())
+        .collect();
+
+    let characteristic = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|characteristic| characteristic.uuid == notify_uuid)
+        .ok_or_else(|| format!("Characteristic {} not found", notify_uuid))?;
+    if !characteristic.properties.contains(CharPropFlags::NOTIFY) {
+        return Err(format!(
+            "Characteristic {} doesn't support notifications",
+            notify_uuid
+        ));
+    }
+
+    let mut notifications = peripheral
+        .notifications()
+        .await
+        .map_err(|err| format!("Subscribe failed: {}", err))?;
+    timeout(args.ble_timeout(), peripheral.subscribe(&characteristic))
+        .await
+        .map_err(|_| String::from("Subscribe timed out"))?
+        .map_err(|err| format!("Subscribe failed: {}", err))?;
+
+    loop {
+        let data = timeout(args.ble_timeout(), notifications.next())
+            .await
+            .map_err(|_| String::from("No notification received"))?
+            .ok_or_else(|| String::from("Notification stream closed"))?;
+        if data.uuid != notify_uuid {
+            continue;
+        }
+
+        return match decode_moisture(&data.value, args.moisture_endian) {
+            Some(reading) => {
+                report.first_reading = Some(reading);
+                Ok(())
+            }
+            None => Err(format!(
+                "Notification too short to decode: {:02x?}",
+                data.value
+            )),
+        };
+    }
+}
@@ -0,0 +1,42 @@
+use sensor_exporter::{decode_moisture, decode_signed, Endian};
+
+#[test]
+fn decodes_two_bytes_little_endian() {
+    assert_eq!(decode_moisture(&[0x34, 0x12], Endian::Little), Some(0x1234));
+    assert_eq!(decode_moisture(&[0xff, 0x0f], Endian::Little), Some(4095));
+}
+
+#[test]
+fn decodes_two_bytes_big_endian() {
+    assert_eq!(decode_moisture(&[0x12, 0x34], Endian::Big), Some(0x1234));
+    assert_eq!(decode_moisture(&[0x0f, 0xff], Endian::Big), Some(4095));
+}
+
+#[test]
+fn byte_order_swaps_the_value() {
+    let bytes = [0x01, 0x02];
+    assert_eq!(decode_moisture(&bytes, Endian::Little), Some(0x0201));
+    assert_eq!(decode_moisture(&bytes, Endian::Big), Some(0x0102));
+}
+
+#[test]
+fn rejects_short_payloads() {
+    for endian in [Endian::Little, Endian::Big] {
+        assert_eq!(decode_moisture(&[], endian), None);
+        assert_eq!(decode_moisture(&[0x34], endian), None);
+    }
+}
+
+#[test]
+fn ignores_trailing_bytes() {
+    assert_eq!(decode_moisture(&[0x34, 0x12, 0xab, 0xcd], Endian::Little), Some(0x1234));
+    assert_eq!(decode_moisture(&[0x12, 0x34, 0xab, 0xcd], Endian::Big), Some(0x1234));
+}
+
+#[test]
+fn decodes_signed_values() {
+    assert_eq!(decode_signed(&[0x18, 0xfc], Endian::Little), Some(-1000));
+    assert_eq!(decode_signed(&[0xfc, 0x18], Endian::Big), Some(-1000));
+    assert_eq!(decode_signed(&[0xe8, 0x03], Endian::Little), Some(1000));
+    assert_eq!(decode_signed(&[0xe8], Endian::Little), None);
+}
@@ -0,0 +1,95 @@
+//! Runs the exporter with synthetic sensors and scrapes it like Prometheus would.
+#![cfg(feature = "mock-ble")]
+
+use std::env;
+use std::net::TcpListener;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Kills the exporter when the test ends, passed or not.
+struct Exporter(Child);
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Value of the first `soil_moisture` series of `device` in a text format scrape.
+fn moisture(scrape: &str, device: &str) -> Option<f64> {
+    let series = format!("soil_moisture{{device=\"{}\"}}", device);
+    scrape
+        .lines()
+        .find_map(|line| line.strip_prefix(&series))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Scrapes until `device` has a moisture reading or `timeout` passes.
+fn scrape_moisture(url: &str, device: &str, timeout: Duration) -> f64 {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let scrape = ureq::get(url)
+            .call()
+            .ok()
+            .and_then(|response| response.into_string().ok());
+        if let Some(value) = scrape.as_deref().and_then(|scrape| moisture(scrape, device)) {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "no reading of {} at {}", device, url);
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Starts the exporter on a free port, returning it with its base URL.
+fn start_exporter() -> (Exporter, String) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("free port")
+        .port();
+    let exporter = Exporter(
+        Command::new(env!("CARGO_BIN_EXE_sensor-exporter"))
+            .args(["--bind-addr", &format!("127.0.0.1:{}", port)])
+            .args(["--moisture-stale-secs", "0"])
+            .arg("--state-file")
+            .arg(env::temp_dir().join(format!("sensor-exporter-mock-{}.json", port)))
+            .spawn()
+            .expect("exporter starts"),
+    );
+    (exporter, format!("http://127.0.0.1:{}", port))
+}
+
+#[test]
+fn mock_sensor_gauge_moves() {
+    let (_exporter, base_url) = start_exporter();
+    let url = format!("{}/metrics", base_url);
+
+    let first = scrape_moisture(&url, "MockBit-1", Duration::from_secs(10));
+    thread::sleep(Duration::from_secs(3));
+    let second = scrape_moisture(&url, "MockBit-1", Duration::from_secs(10));
+
+    assert!((1048.0..=3048.0).contains(&first), "{} out of the wave", first);
+    assert_ne!(first, second, "gauge didn't move");
+}
+
+#[test]
+fn readings_endpoint_reports_mock_sensor() {
+    let (_exporter, base_url) = start_exporter();
+    scrape_moisture(&format!("{}/metrics", base_url), "MockBit-1", Duration::from_secs(10));
+
+    let body = ureq::get(&format!("{}/readings", base_url))
+        .call()
+        .expect("readings served")
+        .into_string()
+        .expect("readings body");
+    let readings: serde_json::Value = serde_json::from_str(&body).expect("readings are JSON");
+    let sensor = &readings["MockBit-1"];
+
+    let raw = sensor["raw"].as_f64().expect("raw reading");
+    assert!((1048.0..=3048.0).contains(&raw), "{} out of the wave", raw);
+    assert_eq!(sensor["connected"], true);
+    assert!(sensor["last_seen"].as_f64().is_some(), "no last seen time");
+    assert!(sensor["percent"].is_null(), "percent without calibration");
+}